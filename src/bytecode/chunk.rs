@@ -1,3 +1,9 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Write};
+
+use super::object::Obj;
+use super::shape::InlineCache;
 use super::value::{Value, ValueArray};
 use crate::impl_convert_enum_u8;
 
@@ -5,6 +11,7 @@ use crate::impl_convert_enum_u8;
 #[repr(u8)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
     Nil,
     True,
     False,
@@ -12,11 +19,25 @@ pub enum OpCode {
     GetLocal,
     SetLocal,
     GetGlobal,
+    GetGlobalLong,
     DefineGlobal,
+    DefineGlobalLong,
     Equal,
+    NotEqual,
     SetGlobal,
+    SetGlobalLong,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    GetSuper,
+    GetSuperLong,
     Greater,
     Less,
+    GreaterEqual,
+    LessEqual,
     Add,
     Subtract,
     Multiply,
@@ -26,25 +47,87 @@ pub enum OpCode {
     Print,
     Jump,
     JumpIfFalse,
+    JumpIfTrue,
     Loop,
     Call,
+    Invoke,
+    InvokeLong,
+    SuperInvoke,
+    SuperInvokeLong,
     Closure,
+    ClosureLong,
+    CloseUpvalue,
+    Class,
+    ClassLong,
+    Inherit,
+    Method,
+    MethodLong,
+    // Superinstructions, which the optimizer fuses from common sequences.
+    /// `GetLocal`, `Constant` and `Add`, for a number constant.
+    AddLocalConstant,
+    /// `GetLocal`, `Constant` and `Subtract`, for a number constant.
+    SubtractLocalConstant,
+    /// `Less` and `JumpIfFalse`, popping the comparison on both branches.
+    LessJumpIfFalse,
+    /// `Greater` and `JumpIfFalse`, popping the comparison on both branches.
+    GreaterJumpIfFalse,
     Return,
 }
 
 impl_convert_enum_u8!(OpCode, Return);
 
-#[derive(Clone, Default, Debug, Hash, PartialEq, Eq)]
-struct LineNumber {
-    pub number: u32,
-    pub count: u32,
+/// Largest constant index the long opcodes can encode in their 24-bit operand.
+pub const MAX_LONG_CONSTANT: usize = (1 << 24) - 1;
+
+impl OpCode {
+    /// Whether the constant operand is three bytes wide instead of one.
+    pub fn has_long_operand(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::GetGlobalLong
+                | OpCode::DefineGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::GetSuperLong
+                | OpCode::InvokeLong
+                | OpCode::SuperInvokeLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::MethodLong
+        )
+    }
+}
+
+/// Where in the source an instruction came from.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A run of code that all comes from the same position, starting at byte `start`.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub(super) struct LineRun {
+    pub start: u32,
+    pub position: Position,
 }
 
-#[derive(Clone, Default, Debug, Hash, PartialEq, Eq)]
+#[derive(Clone, Default, Debug, PartialEq)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    lines: Vec<LineNumber>,
+    /// Sorted by `start`, so the position of an offset is a binary search away.
+    pub(super) lines: Vec<LineRun>,
     pub constants: ValueArray,
+    /// One cache for every property access and invoke, filled in as the code runs.
+    pub caches: Vec<Cell<InlineCache>>,
 }
 
 impl Chunk {
@@ -53,121 +136,284 @@ impl Chunk {
             code: Default::default(),
             lines: Default::default(),
             constants: Default::default(),
+            caches: Default::default(),
         }
     }
 
-    pub fn write(&mut self, byte: u8, line: u32) {
+    pub fn write(&mut self, byte: u8, position: Position) {
+        if self.lines.last().map(|run| run.position) != Some(position) {
+            self.lines.push(LineRun { start: self.code.len() as u32, position });
+        }
         self.code.push(byte);
-        if let Some(last_line) = self.lines.last_mut() {
-            if last_line.number == line {
-                last_line.count += 1;
-            } else {
-                self.lines.push(LineNumber { number: line, count: 1 });
-            }
-        } else {
-            self.lines.push(LineNumber { number: line, count: 1 });
+    }
+
+    /// Drops the code from `len` onwards along with its line information.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        while self.lines.last().is_some_and(|run| run.start as usize >= len) {
+            self.lines.pop();
         }
     }
 
-    pub fn add_constant(&mut self, value: Value) -> usize {
+    /// Adds `value` to the constants, or returns `None` if its index wouldn't fit in the operand of
+    /// a long instruction.
+    pub fn add_constant(&mut self, value: Value) -> Option<usize> {
+        if self.constants.len() > MAX_LONG_CONSTANT {
+            return None;
+        }
         self.constants.push(value);
-        return self.constants.len() - 1;
+        Some(self.constants.len() - 1)
+    }
+
+    pub fn add_cache(&mut self) -> usize {
+        self.caches.push(Default::default());
+        self.caches.len() - 1
     }
 }
 
 impl Chunk {
-    pub fn disassemble(&self, name: &str) {
-        println!("== {name} ==");
+    pub fn disassemble(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "== {name} ==")?;
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, out)?;
         }
+        Ok(())
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{offset:04} ");
-        if offset > 0 && self.get_line(offset) == self.get_line(offset - 1) {
-            print!("   | ");
+    pub fn disassemble_instruction(&self, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        write!(out, "{offset:04} ")?;
+        let position = self.get_position(offset);
+        if offset > 0 && position.line == self.get_position(offset - 1).line {
+            write!(out, "   |:{:<3} ", position.column)?;
         } else {
-            print!("{:04} ", self.get_line(offset));
+            write!(out, "{:04}:{:<3} ", position.line, position.column)?;
         }
 
         let instruction = self.code[offset];
-        return match instruction.try_into() {
-            Ok(OpCode::Constant) => self.constant_instruction("OpConstant", offset),
-            Ok(OpCode::Nil) => self.simple_instruction("OpNil", offset),
-            Ok(OpCode::True) => self.simple_instruction("OpTrue", offset),
-            Ok(OpCode::False) => self.simple_instruction("OpFalse", offset),
-            Ok(OpCode::Pop) => self.simple_instruction("OpPop", offset),
-            Ok(OpCode::SetGlobal) => self.constant_instruction("OpSetGlobal", offset),
-            Ok(OpCode::Equal) => self.simple_instruction("OpEqual", offset),
-            Ok(OpCode::GetLocal) => self.byte_instruction("OpGetLocal", offset),
-            Ok(OpCode::SetLocal) => self.byte_instruction("OpSetLocal", offset),
-            Ok(OpCode::GetGlobal) => self.constant_instruction("OpGetGlobal", offset),
-            Ok(OpCode::DefineGlobal) => self.constant_instruction("OpDefineGlobal", offset),
-            Ok(OpCode::Greater) => self.simple_instruction("OpGreater", offset),
-            Ok(OpCode::Less) => self.simple_instruction("OpLess", offset),
-            Ok(OpCode::Add) => self.simple_instruction("OpAdd", offset),
-            Ok(OpCode::Subtract) => self.simple_instruction("OpSubtract", offset),
-            Ok(OpCode::Multiply) => self.simple_instruction("OpMultiply", offset),
-            Ok(OpCode::Divide) => self.simple_instruction("OpDivide", offset),
-            Ok(OpCode::Not) => self.simple_instruction("OpNot", offset),
-            Ok(OpCode::Negate) => self.simple_instruction("OpNegate", offset),
-            Ok(OpCode::Jump) => self.jump_instruction("OpJump", 1, offset),
-            Ok(OpCode::JumpIfFalse) => self.jump_instruction("OpJumpIfFalse", 1, offset),
-            Ok(OpCode::Print) => self.simple_instruction("OpPrint", offset),
-            Ok(OpCode::Loop) => self.jump_instruction("OpLoop", -1, offset),
-            Ok(OpCode::Call) => self.byte_instruction("OpCall", offset),
-            Ok(OpCode::Closure) => {
-                let constant = self.code[offset + 1];
-                print!("{:-16} {:04}", "OpClosure", constant);
-                println!();
-                offset + 2
+        match instruction.try_into() {
+            Ok(OpCode::Constant) => self.constant_instruction("OpConstant", offset, out),
+            Ok(OpCode::ConstantLong) => self.constant_long_instruction("OpConstantLong", offset, out),
+            Ok(OpCode::Nil) => self.simple_instruction("OpNil", offset, out),
+            Ok(OpCode::True) => self.simple_instruction("OpTrue", offset, out),
+            Ok(OpCode::False) => self.simple_instruction("OpFalse", offset, out),
+            Ok(OpCode::Pop) => self.simple_instruction("OpPop", offset, out),
+            Ok(OpCode::SetGlobal) => self.byte_instruction("OpSetGlobal", offset, out),
+            Ok(OpCode::SetGlobalLong) => self.long_instruction("OpSetGlobalLong", offset, out),
+            Ok(OpCode::Equal) => self.simple_instruction("OpEqual", offset, out),
+            Ok(OpCode::NotEqual) => self.simple_instruction("OpNotEqual", offset, out),
+            Ok(OpCode::GetLocal) => self.byte_instruction("OpGetLocal", offset, out),
+            Ok(OpCode::SetLocal) => self.byte_instruction("OpSetLocal", offset, out),
+            Ok(OpCode::GetGlobal) => self.byte_instruction("OpGetGlobal", offset, out),
+            Ok(OpCode::GetGlobalLong) => self.long_instruction("OpGetGlobalLong", offset, out),
+            Ok(OpCode::DefineGlobal) => self.byte_instruction("OpDefineGlobal", offset, out),
+            Ok(OpCode::DefineGlobalLong) => self.long_instruction("OpDefineGlobalLong", offset, out),
+            Ok(OpCode::GetUpvalue) => self.byte_instruction("OpGetUpvalue", offset, out),
+            Ok(OpCode::SetUpvalue) => self.byte_instruction("OpSetUpvalue", offset, out),
+            Ok(OpCode::GetProperty) => self.property_instruction("OpGetProperty", offset, false, out),
+            Ok(OpCode::GetPropertyLong) => self.property_instruction("OpGetPropertyLong", offset, true, out),
+            Ok(OpCode::SetProperty) => self.property_instruction("OpSetProperty", offset, false, out),
+            Ok(OpCode::SetPropertyLong) => self.property_instruction("OpSetPropertyLong", offset, true, out),
+            Ok(OpCode::GetSuper) => self.constant_instruction("OpGetSuper", offset, out),
+            Ok(OpCode::GetSuperLong) => self.constant_long_instruction("OpGetSuperLong", offset, out),
+            Ok(OpCode::Greater) => self.simple_instruction("OpGreater", offset, out),
+            Ok(OpCode::Less) => self.simple_instruction("OpLess", offset, out),
+            Ok(OpCode::GreaterEqual) => self.simple_instruction("OpGreaterEqual", offset, out),
+            Ok(OpCode::LessEqual) => self.simple_instruction("OpLessEqual", offset, out),
+            Ok(OpCode::Add) => self.simple_instruction("OpAdd", offset, out),
+            Ok(OpCode::Subtract) => self.simple_instruction("OpSubtract", offset, out),
+            Ok(OpCode::Multiply) => self.simple_instruction("OpMultiply", offset, out),
+            Ok(OpCode::Divide) => self.simple_instruction("OpDivide", offset, out),
+            Ok(OpCode::Not) => self.simple_instruction("OpNot", offset, out),
+            Ok(OpCode::Negate) => self.simple_instruction("OpNegate", offset, out),
+            Ok(OpCode::Jump) => self.jump_instruction("OpJump", 1, offset, out),
+            Ok(OpCode::JumpIfFalse) => self.jump_instruction("OpJumpIfFalse", 1, offset, out),
+            Ok(OpCode::JumpIfTrue) => self.jump_instruction("OpJumpIfTrue", 1, offset, out),
+            Ok(OpCode::Print) => self.simple_instruction("OpPrint", offset, out),
+            Ok(OpCode::Loop) => self.jump_instruction("OpLoop", -1, offset, out),
+            Ok(OpCode::Call) => self.byte_instruction("OpCall", offset, out),
+            Ok(OpCode::Invoke) => self.invoke_instruction("OpInvoke", offset, false, true, out),
+            Ok(OpCode::InvokeLong) => self.invoke_instruction("OpInvokeLong", offset, true, true, out),
+            Ok(OpCode::SuperInvoke) => self.invoke_instruction("OpSuperInvoke", offset, false, false, out),
+            Ok(OpCode::SuperInvokeLong) => self.invoke_instruction("OpSuperInvokeLong", offset, true, false, out),
+            Ok(OpCode::Closure) => self.closure_instruction("OpClosure", offset, false, out),
+            Ok(OpCode::ClosureLong) => self.closure_instruction("OpClosureLong", offset, true, out),
+            Ok(OpCode::CloseUpvalue) => self.simple_instruction("OpCloseUpvalue", offset, out),
+            Ok(OpCode::Class) => self.constant_instruction("OpClass", offset, out),
+            Ok(OpCode::ClassLong) => self.constant_long_instruction("OpClassLong", offset, out),
+            Ok(OpCode::Inherit) => self.simple_instruction("OpInherit", offset, out),
+            Ok(OpCode::Method) => self.constant_instruction("OpMethod", offset, out),
+            Ok(OpCode::MethodLong) => self.constant_long_instruction("OpMethodLong", offset, out),
+            Ok(OpCode::AddLocalConstant) => self.local_constant_instruction("OpAddLocalConstant", offset, out),
+            Ok(OpCode::SubtractLocalConstant) => {
+                self.local_constant_instruction("OpSubtractLocalConstant", offset, out)
             }
-            Ok(OpCode::Return) => self.simple_instruction("OpReturn", offset),
+            Ok(OpCode::LessJumpIfFalse) => self.jump_instruction("OpLessJumpIfFalse", 1, offset, out),
+            Ok(OpCode::GreaterJumpIfFalse) => self.jump_instruction("OpGreaterJumpIfFalse", 1, offset, out),
+            Ok(OpCode::Return) => self.simple_instruction("OpReturn", offset, out),
             Err(_) => {
-                println!("Unknown opcode {:?}", &instruction);
-                offset + 1
+                writeln!(out, "Unknown opcode {:?}", &instruction)?;
+                Ok(offset + 1)
             }
-        };
+        }
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{}", name);
-        offset + 1
+    fn simple_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        writeln!(out, "{}", name)?;
+        Ok(offset + 1)
     }
 
-    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+    fn byte_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
         let slot = self.code[offset + 1];
-        println!("{:-16} {:04}", name, slot);
-        offset + 2
+        writeln!(out, "{:-16} {:04}", name, slot)?;
+        Ok(offset + 2)
+    }
+
+    fn long_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        let slot = self.read_long(offset + 1);
+        writeln!(out, "{:-16} {:04}", name, slot)?;
+        Ok(offset + 4)
     }
 
-    fn jump_instruction(&self, name: &str, sign: i8, offset: usize) -> usize {
+    fn jump_instruction(&self, name: &str, sign: i8, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
         let mut jump = (self.code[offset + 1] as u16) << 8;
         jump |= self.code[offset + 2] as u16;
-        println!("{:-16} {:04} -> {:04}", name, offset, offset as isize + 3 + sign as isize * jump as isize);
-        offset + 3
+        writeln!(out, "{:-16} {:04} -> {:04}", name, offset, offset as isize + 3 + sign as isize * jump as isize)?;
+        Ok(offset + 3)
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+    fn constant_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
         let constant = self.code[offset + 1];
-        println!("{:-16} {:04} '{}'", name, constant, self.constants[constant as usize]);
-        return offset + 2;
-    }
-
-    pub fn get_line(&self, index: usize) -> u32 {
-        let mut number = 0;
-        let mut current_position = 0;
-        for line in &self.lines {
-            if current_position > index {
-                break;
-            } else {
-                current_position += line.count as usize;
-                number = line.number;
+        writeln!(out, "{:-16} {:04} '{}'", name, constant, self.constants[constant as usize])?;
+        Ok(offset + 2)
+    }
+
+    fn constant_long_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        let constant = self.read_long(offset + 1);
+        writeln!(out, "{:-16} {:04} '{}'", name, constant, self.constants[constant])?;
+        Ok(offset + 4)
+    }
+
+    fn local_constant_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        let slot = self.code[offset + 1];
+        let constant = self.code[offset + 2];
+        writeln!(out, "{:-16} {:04} {:04} '{}'", name, slot, constant, self.constants[constant as usize])?;
+        Ok(offset + 3)
+    }
+
+    fn property_instruction(&self, name: &str, offset: usize, long: bool, out: &mut dyn Write) -> io::Result<usize> {
+        let (constant, offset) = self.constant_operand(offset + 1, long);
+        let cache = self.read_short(offset);
+        writeln!(out, "{:-16} {:04} '{}' cache {}", name, constant, self.constants[constant], cache)?;
+        Ok(offset + 2)
+    }
+
+    fn invoke_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        long: bool,
+        cached: bool,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let (constant, offset) = self.constant_operand(offset + 1, long);
+        let arg_count = self.code[offset];
+        write!(out, "{:-16} ({} args) {:04} '{}'", name, arg_count, constant, self.constants[constant])?;
+        if cached {
+            writeln!(out, " cache {}", self.read_short(offset + 1))?;
+            Ok(offset + 3)
+        } else {
+            writeln!(out)?;
+            Ok(offset + 1)
+        }
+    }
+
+    /// Reads the constant index at `offset`, returning it and the offset after it.
+    fn constant_operand(&self, offset: usize, long: bool) -> (usize, usize) {
+        if long {
+            (self.read_long(offset), offset + 3)
+        } else {
+            (self.code[offset] as usize, offset + 1)
+        }
+    }
+
+    fn closure_instruction(&self, name: &str, offset: usize, long: bool, out: &mut dyn Write) -> io::Result<usize> {
+        let (constant, mut offset) = self.constant_operand(offset + 1, long);
+        let value = &self.constants[constant];
+        writeln!(out, "{:-16} {:04} {}", name, constant, value)?;
+
+        if let Some(Obj::Function(function)) = value.as_obj() {
+            for _ in 0..function.upvalue_count {
+                let is_local = self.code[offset];
+                let index = self.code[offset + 1];
+                writeln!(
+                    out,
+                    "{:04}    |                     {} {}",
+                    offset,
+                    if is_local == 1 { "local" } else { "upvalue" },
+                    index
+                )?;
+                offset += 2;
             }
         }
-        return number;
+        Ok(offset)
+    }
+
+    /// Length in bytes of the instruction at `offset`, operands included.
+    pub fn instruction_len(&self, offset: usize) -> usize {
+        let op = OpCode::try_from(self.code[offset]).unwrap();
+        // The opcode and its constant operand, if it has one.
+        let constant_len = 1 + if op.has_long_operand() { 3 } else { 1 };
+        match op {
+            OpCode::Closure | OpCode::ClosureLong => {
+                let (constant, _) = self.constant_operand(offset + 1, op.has_long_operand());
+                match self.constants[constant].as_obj() {
+                    Some(Obj::Function(function)) => constant_len + 2 * function.upvalue_count,
+                    _ => constant_len,
+                }
+            }
+            OpCode::Constant | OpCode::ConstantLong | OpCode::GetGlobal | OpCode::GetGlobalLong
+            | OpCode::DefineGlobal | OpCode::DefineGlobalLong | OpCode::SetGlobal | OpCode::SetGlobalLong
+            | OpCode::GetSuper | OpCode::GetSuperLong | OpCode::Class | OpCode::ClassLong | OpCode::Method
+            | OpCode::MethodLong => constant_len,
+            // Followed by the inline cache.
+            OpCode::GetProperty | OpCode::GetPropertyLong | OpCode::SetProperty | OpCode::SetPropertyLong => {
+                constant_len + 2
+            }
+            // Followed by the argument count, and for `Invoke` the inline cache.
+            OpCode::Invoke | OpCode::InvokeLong => constant_len + 3,
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => constant_len + 1,
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::GetUpvalue | OpCode::SetUpvalue | OpCode::Call => 2,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue | OpCode::Loop | OpCode::AddLocalConstant
+            | OpCode::SubtractLocalConstant | OpCode::LessJumpIfFalse | OpCode::GreaterJumpIfFalse => 3,
+            _ => 1,
+        }
+    }
+
+    /// The position of every byte of code, expanded from the run table.
+    pub fn byte_positions(&self) -> Vec<Position> {
+        let mut positions = Vec::with_capacity(self.code.len());
+        for (index, run) in self.lines.iter().enumerate() {
+            let end = self.lines.get(index + 1).map_or(self.code.len(), |next| next.start as usize);
+            positions.resize(end, run.position);
+        }
+        positions
+    }
+
+    /// Reads a 24-bit operand stored high byte first.
+    pub fn read_long(&self, offset: usize) -> usize {
+        (self.code[offset] as usize) << 16 | (self.code[offset + 1] as usize) << 8 | self.code[offset + 2] as usize
+    }
+
+    pub fn read_short(&self, offset: usize) -> usize {
+        (self.code[offset] as usize) << 8 | self.code[offset + 1] as usize
+    }
+
+    pub fn get_position(&self, offset: usize) -> Position {
+        match self.lines.partition_point(|run| run.start as usize <= offset) {
+            0 => Position::default(),
+            run => self.lines[run - 1].position,
+        }
     }
 }
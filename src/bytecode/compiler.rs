@@ -1,9 +1,14 @@
+use std::cmp::Ordering;
 use std::mem;
 
 use super::scanner::{Scanner, Token, TokenType};
-use super::chunk::{Chunk, OpCode};
-use super::object::{Obj, Function, StringObj};
+use super::chunk::{Chunk, OpCode, Position, MAX_LONG_CONSTANT};
+use super::error::{Diagnostic, Location};
+use super::memory::{Gc, Heap};
+use super::optimizer;
+use super::object::{Obj, Object, Function, StringObj};
 use super::value::Value;
+use super::vm::VM;
 use crate::impl_convert_enum_u8;
 
 #[derive(Debug, PartialEq, PartialOrd)]
@@ -30,14 +35,17 @@ impl Precedence {
 
 impl_convert_enum_u8!(Precedence, Primary);
 
-pub fn compile(source: &str) -> Option<Function> {
+pub fn compile<'a>(source: &'a str, vm: &'a mut VM) -> Result<Gc<Function>, Vec<Diagnostic>> {
     let mut parser = Parser {
         current: Default::default(),
         previous: Default::default(),
         had_error: false,
+        diagnostics: Vec::new(),
         panic_mode: false,
         scanner: Scanner::new(source),
         compiler: Compiler::new(),
+        class_compiler: None,
+        vm,
     };
 
     parser.advance();
@@ -48,7 +56,19 @@ pub fn compile(source: &str) -> Option<Function> {
 
     parser.consume(TokenType::Eof, "Expect end of expression.");
     let function = parser.end_compiler();
-    (!parser.had_error).then_some(function)
+    if parser.had_error {
+        Err(parser.diagnostics)
+    } else {
+        Ok(function)
+    }
+}
+
+fn synthetic_token(text: &str) -> Token {
+    Token {
+        r#type: TokenType::Identifier,
+        lexeme: text.into(),
+        ..Default::default()
+    }
 }
 
 type ParseFn = fn(&mut Parser, bool);
@@ -64,21 +84,43 @@ pub struct ParseRule {
 struct Local {
     pub name: Token,
     pub depth: Option<usize>,
+    pub is_captured: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue {
+    pub index: u8,
+    pub is_local: bool,
 }
 
 #[derive(Clone, Copy, PartialEq)]
 enum FunctionType {
     Function,
+    Initializer,
+    Method,
     Script,
 }
 
+/// An instruction that loads a constant value, kept so an operator applied to it can be folded.
+#[derive(Clone, Copy)]
+struct ConstantLoad {
+    start: usize,
+    end: usize,
+    value: Value,
+    /// Index in the constant table, or `None` for `nil`, `true` and `false`.
+    constant: Option<usize>,
+}
+
 struct Compiler {
     enclosing: Option<Box<Compiler>>,
     function: Option<Box<Function>>,
     r#type: FunctionType,
 
     locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
     scope_depth: usize,
+    /// The most recent constant load, as long as nothing has been emitted or patched since.
+    last_constant: Option<ConstantLoad>,
 }
 
 impl Compiler {
@@ -88,7 +130,9 @@ impl Compiler {
             function: None,
             r#type: FunctionType::Script,
             locals: Vec::with_capacity(u8::MAX as usize + 1),
+            upvalues: Vec::with_capacity(u8::MAX as usize + 1),
             scope_depth: 0,
+            last_constant: None,
         };
 
         result.function = Some(Function::new().into());
@@ -96,27 +140,31 @@ impl Compiler {
             Local {
                 depth: Some(0),
                 name: Default::default(),
+                is_captured: false,
             }
         );
 
         result
     }
 
-    pub fn push_new(&mut self, r#type: FunctionType, name: &Token) {
-        let function = (r#type != FunctionType::Script).then_some(
-            Function {
-                arity: 0,
-                chunk: Chunk::new(),
-                name: Some(StringObj::new(name.lexeme.to_string()).into()),
-            }.into()
-        );
+    pub fn push_new(&mut self, r#type: FunctionType, name: Gc<StringObj>) {
+        let function = Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name: Some(name),
+        };
 
         let new = Compiler::new();
         let enclosing = std::mem::replace(self, new);
 
         self.enclosing = Some(enclosing.into());
-        self.function = function;
+        self.function = Some(function.into());
         self.r#type = r#type;
+
+        if r#type != FunctionType::Function {
+            self.locals[0].name = synthetic_token("this");
+        }
     }
 
     pub fn pop(&mut self) {
@@ -125,15 +173,78 @@ impl Compiler {
             mem::swap(self, &mut enclosing);
         }
     }
+
+    fn mark_roots(&self, heap: &mut Heap) {
+        if let Some(function) = &self.function {
+            function.trace(heap);
+        }
+        if let Some(enclosing) = &self.enclosing {
+            enclosing.mark_roots(heap);
+        }
+    }
+
+    fn resolve_local(&self, name: &Token) -> Result<Option<u8>, &'static str> {
+        for (i, local) in self.locals.iter().enumerate().rev() {
+            if *name.lexeme == *local.name.lexeme {
+                if local.depth.is_none() {
+                    return Err("Can't read local variable in its own initializer.");
+                }
+                return Ok(Some(i.try_into().unwrap()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, &'static str> {
+        let upvalue = Upvalue { index, is_local };
+        if let Some(i) = self.upvalues.iter().position(|existing| *existing == upvalue) {
+            return Ok(i as u8);
+        }
+
+        if self.upvalues.len() > u8::MAX.into() {
+            return Err("Too many closure variables in function.");
+        }
+
+        self.upvalues.push(upvalue);
+        let function = self.function.as_mut().unwrap();
+        function.upvalue_count = self.upvalues.len();
+        Ok((self.upvalues.len() - 1) as u8)
+    }
+
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<u8>, &'static str> {
+        let Some(enclosing) = self.enclosing.as_mut() else {
+            return Ok(None);
+        };
+
+        if let Some(local) = enclosing.resolve_local(name)? {
+            enclosing.locals[local as usize].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue, false).map(Some);
+        }
+
+        Ok(None)
+    }
+}
+
+struct ClassCompiler {
+    enclosing: Option<Box<ClassCompiler>>,
+    has_superclass: bool,
 }
 
 pub struct Parser<'a> {
     current: Token,
     previous: Token,
     had_error: bool,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
     scanner: Scanner<'a>,
     compiler: Compiler,
+    class_compiler: Option<Box<ClassCompiler>>,
+    vm: &'a mut VM,
 }
 
 impl Parser<'_> {
@@ -176,53 +287,114 @@ impl Parser<'_> {
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        let line = self.previous.line;
-        self.current_chunk().write(byte, line);
+        self.emit_byte_at(byte, self.previous.position());
+    }
+
+    /// Emits a byte attributed to `position` rather than the token just consumed, so an operator's
+    /// errors point at the operator and not at its last operand.
+    fn emit_byte_at(&mut self, byte: u8, position: Position) {
+        self.current_chunk().write(byte, position);
+    }
+
+    fn allocate<T: Object>(&mut self, object: T) -> Gc<T> {
+        let Parser { vm, compiler, .. } = self;
+        vm.allocate_with_roots(object, |heap| compiler.mark_roots(heap))
+    }
+
+    fn intern(&mut self, string: String) -> Gc<StringObj> {
+        let Parser { vm, compiler, .. } = self;
+        vm.intern_with_roots(string, |heap| compiler.mark_roots(heap))
     }
 
-    fn end_compiler(&mut self) -> Function {
+    fn end_compiler(&mut self) -> Gc<Function> {
         self.emit_return();
-        let function = *self.compiler.function.clone().unwrap();
+        let mut function = *self.compiler.function.take().unwrap();
 
-        #[cfg(debug_assertions)]
-        if !self.had_error {
-            self.current_chunk().disassemble(&function.to_string());
+        if self.vm.options().optimize && !self.had_error {
+            optimizer::optimize(&mut function.chunk);
+        }
+
+        if self.vm.options().print_code && !self.had_error {
+            // Like the VM's trace, this is best effort.
+            let _ = function.chunk.disassemble(&function.to_string(), &mut self.vm.trace);
         }
 
         self.compiler.pop();
-        function
+        self.allocate(function)
     }
 
     fn binary(&mut self, _can_assign: bool) {
         let operator_type = self.previous.r#type;
+        let operator = self.previous.position();
+        let left = self.constant_operand();
         let rule = self.get_rule(operator_type).unwrap();
         self.parse_precedence(rule.precedence.next_highest().unwrap());
 
-        match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal.into(), OpCode::Not.into()),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal.into()),
-            TokenType::Greater => self.emit_byte(OpCode::Greater.into()),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less.into(), OpCode::Not.into()),
-            TokenType::Less => self.emit_byte(OpCode::Less.into()),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater.into(), OpCode::Not.into()),
-            TokenType::Plus => self.emit_byte(OpCode::Add.into()),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract.into()),
-            TokenType::Star => self.emit_byte(OpCode::Multiply.into()),
-            TokenType::Slash => self.emit_byte(OpCode::Divide.into()),
-            _ => unreachable!(),
+        if let (Some(left), Some(right)) = (left, self.constant_operand()) {
+            if right.start == left.end {
+                if let Some(value) = self.fold_binary(operator_type, left.value, right.value) {
+                    self.replace_with_constant(&[left, right], value);
+                    return;
+                }
+            }
         }
+
+        let op = match operator_type {
+            TokenType::BangEqual => OpCode::NotEqual,
+            TokenType::EqualEqual => OpCode::Equal,
+            TokenType::Greater => OpCode::Greater,
+            TokenType::GreaterEqual => OpCode::GreaterEqual,
+            TokenType::Less => OpCode::Less,
+            TokenType::LessEqual => OpCode::LessEqual,
+            TokenType::Plus => OpCode::Add,
+            TokenType::Minus => OpCode::Subtract,
+            TokenType::Star => OpCode::Multiply,
+            TokenType::Slash => OpCode::Divide,
+            _ => unreachable!(),
+        };
+        self.emit_byte_at(op.into(), operator);
     }
 
     fn call(&mut self, _can_assign: bool) {
+        let paren = self.previous.position();
         let arg_count = self.argument_list();
-        self.emit_bytes(OpCode::Call.into(), arg_count);
+        self.emit_byte_at(OpCode::Call.into(), paren);
+        self.emit_byte_at(arg_count, paren);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let property = self.previous.position();
+        let name = self.identifier_constant(&self.previous.clone());
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_constant_op_at(OpCode::SetProperty, OpCode::SetPropertyLong, name, property);
+        } else if self.matches(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_constant_op_at(OpCode::Invoke, OpCode::InvokeLong, name, property);
+            self.emit_byte_at(arg_count, property);
+        } else {
+            self.emit_constant_op_at(OpCode::GetProperty, OpCode::GetPropertyLong, name, property);
+        }
+        self.emit_cache(property);
+    }
+
+    /// Emits the index of a new inline cache for the instruction just emitted.
+    fn emit_cache(&mut self, position: Position) {
+        let cache = self.current_chunk().add_cache();
+        if cache > u16::MAX.into() {
+            self.error("Too many property accesses in one chunk.");
+        }
+        self.emit_byte_at((cache >> 8) as u8, position);
+        self.emit_byte_at(cache as u8, position);
     }
 
     fn literal(&mut self, _can_assign: bool) {
         match self.previous.r#type {
-            TokenType::False => self.emit_byte(OpCode::False.into()),
-            TokenType::Nil => self.emit_byte(OpCode::Nil.into()),
-            TokenType::True => self.emit_byte(OpCode::True.into()),
+            TokenType::False => self.emit_value(Value::bool(false)),
+            TokenType::Nil => self.emit_value(Value::NIL),
+            TokenType::True => self.emit_value(Value::bool(true)),
             _ => unreachable!(),
         }
     }
@@ -234,7 +406,7 @@ impl Parser<'_> {
 
     fn number(&mut self, _can_assign: bool) {
         let value = self.previous.lexeme.parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(value.into()));
+        self.emit_constant(Value::number(value));
     }
 
     fn or(&mut self, _can_assign: bool) {
@@ -249,30 +421,37 @@ impl Parser<'_> {
     }
 
     fn string(&mut self, _can_assign: bool) {
-        self.emit_constant(Value::Obj(Obj::String(StringObj::new(self.previous.lexeme[1..self.previous.lexeme.len() - 1].to_string()).into())));
+        let string = self.intern(self.previous.lexeme[1..self.previous.lexeme.len() - 1].to_string());
+        self.emit_constant(Value::obj(Obj::String(string)));
     }
 
     fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let arg = self.resolve_local(&name);
-        let (arg, get_op, set_op) = if let Some(arg) = arg {
+        let (arg, get_op, set_op) = if let Some(arg) = self.resolve_local(&name) {
             (
-                arg,
+                arg.into(),
                 OpCode::GetLocal,
                 OpCode::SetLocal,
             )
-        } else {
+        } else if let Some(arg) = self.resolve_upvalue(&name) {
             (
-                self.identifier_constant(&name),
-                OpCode::GetGlobal,
-                OpCode::SetGlobal,
+                arg.into(),
+                OpCode::GetUpvalue,
+                OpCode::SetUpvalue,
             )
+        } else {
+            let slot = self.global_slot(&name);
+            if slot <= u8::MAX.into() {
+                (slot, OpCode::GetGlobal, OpCode::SetGlobal)
+            } else {
+                (slot, OpCode::GetGlobalLong, OpCode::SetGlobalLong)
+            }
         };
 
         if can_assign && self.matches(TokenType::Equal) {
             self.expression();
-            self.emit_bytes(set_op.into(), arg);
+            self.emit_operand(set_op, arg);
         } else {
-            self.emit_bytes(get_op.into(), arg);
+            self.emit_operand(get_op, arg);
         }
     }
 
@@ -280,18 +459,67 @@ impl Parser<'_> {
         self.named_variable(self.previous.clone(), can_assign);
     }
 
+    fn super_(&mut self, _can_assign: bool) {
+        match &self.class_compiler {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class_compiler) if !class_compiler.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.");
+            }
+            _ => (),
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(&self.previous.clone());
+
+        self.named_variable(synthetic_token("this"), false);
+        if self.matches(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_constant_op(OpCode::SuperInvoke, OpCode::SuperInvokeLong, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_constant_op(OpCode::GetSuper, OpCode::GetSuperLong, name);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.class_compiler.is_none() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+    }
+
     fn unary(&mut self, _can_assign: bool) {
         let operator_type = self.previous.r#type;
+        let operator = self.previous.position();
+        let operand_start = self.current_chunk().code.len();
 
         // Compile the operand
         self.parse_precedence(Precedence::Unary);
 
+        if let Some(operand) = self.constant_operand().filter(|operand| operand.start == operand_start) {
+            let value = match operator_type {
+                TokenType::Bang => Some(Value::bool(operand.value.is_falsey())),
+                TokenType::Minus => operand.value.as_number().map(|number| Value::number(-number)),
+                _ => None,
+            };
+            if let Some(value) = value {
+                self.replace_with_constant(&[operand], value);
+                return;
+            }
+        }
+
         // Emit the operator instruction.
-        match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::Not.into()),
-            TokenType::Minus => self.emit_byte(OpCode::Negate.into()),
+        let op = match operator_type {
+            TokenType::Bang => OpCode::Not,
+            TokenType::Minus => OpCode::Negate,
             _ => unreachable!(),
-        }
+        };
+        self.emit_byte_at(op.into(), operator);
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
@@ -320,8 +548,24 @@ impl Parser<'_> {
         }
     }
 
-    fn identifier_constant(&mut self, name: &Token) -> u8 {
-        self.make_constant(Value::Obj(Obj::String(StringObj::new(name.lexeme.to_string()).into())))
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let string = Value::obj(Obj::String(self.intern(name.lexeme.to_string())));
+        // Identifiers are interned, so every use of a name can share one constant.
+        if let Some(constant) = self.current_chunk().constants.iter().position(|constant| *constant == string) {
+            return constant;
+        }
+        self.make_constant(string)
+    }
+
+    /// The VM's slot for the global called `name`, shared by everything compiled on that VM.
+    fn global_slot(&mut self, name: &Token) -> usize {
+        let name = self.intern(name.lexeme.to_string());
+        let slot = self.vm.global_slot(name);
+        if slot > MAX_LONG_CONSTANT {
+            self.error("Too many global variables.");
+            return 0;
+        }
+        slot
     }
 
     #[inline]
@@ -330,16 +574,17 @@ impl Parser<'_> {
     }
 
     fn resolve_local(&mut self, name: &Token) -> Option<u8> {
-        for (i, local) in self.compiler.locals.iter().enumerate().rev() {
-            if self.identifiers_equal(name, &local.name) {
-                if local.depth.is_none() {
-                    self.error("Can't read local variable in its own initializer.");
-                }
-                return Some(i.try_into().unwrap());
-            }
-        }
+        self.compiler.resolve_local(name).unwrap_or_else(|message| {
+            self.error(message);
+            Some(0)
+        })
+    }
 
-        None
+    fn resolve_upvalue(&mut self, name: &Token) -> Option<u8> {
+        self.compiler.resolve_upvalue(name).unwrap_or_else(|message| {
+            self.error(message);
+            Some(0)
+        })
     }
 
     fn add_local(&mut self, name: &Token) {
@@ -351,6 +596,7 @@ impl Parser<'_> {
         let local = Local {
             name: name.clone(),
             depth: None,
+            is_captured: false,
         };
         self.compiler.locals.push(local);
     }
@@ -376,7 +622,7 @@ impl Parser<'_> {
         self.add_local(name);
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn parse_variable(&mut self, error_message: &str) -> usize {
         self.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
@@ -384,7 +630,7 @@ impl Parser<'_> {
             return 0;
         }
 
-        self.identifier_constant(&self.previous.clone())
+        self.global_slot(&self.previous.clone())
     }
 
     fn mark_initialized(&mut self) {
@@ -393,13 +639,13 @@ impl Parser<'_> {
         }
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: usize) {
         if self.compiler.scope_depth > 0 {
             self.mark_initialized();
             return;
         }
 
-        self.emit_bytes(OpCode::DefineGlobal.into(), global);
+        self.emit_constant_op(OpCode::DefineGlobal, OpCode::DefineGlobalLong, global);
     }
 
     fn argument_list(&mut self) -> u8 {
@@ -454,7 +700,7 @@ impl Parser<'_> {
             TokenType::LeftBrace => parse_rule!(None, None, None),
             TokenType::RightBrace => parse_rule!(None, None, None),
             TokenType::Comma => parse_rule!(None, None, None),
-            TokenType::Dot => parse_rule!(None, None, None),
+            TokenType::Dot => parse_rule!(None, rule_fn!(dot), Call),
             TokenType::Minus => parse_rule!(rule_fn!(unary), rule_fn!(binary), Term),
             TokenType::Plus => parse_rule!(None, rule_fn!(binary), Term),
             TokenType::Semicolon => parse_rule!(None, None, None),
@@ -482,8 +728,8 @@ impl Parser<'_> {
             TokenType::Or => parse_rule!(None, rule_fn!(or), Or),
             TokenType::Print => parse_rule!(None, None, None),
             TokenType::Return => parse_rule!(None, None, None),
-            TokenType::Super => parse_rule!(None, None, None),
-            TokenType::This => parse_rule!(None, None, None),
+            TokenType::Super => parse_rule!(rule_fn!(super_), None, None),
+            TokenType::This => parse_rule!(rule_fn!(this), None, None),
             TokenType::True => parse_rule!(rule_fn!(literal), None, None),
             TokenType::Var => parse_rule!(None, None, None),
             TokenType::While => parse_rule!(None, None, None),
@@ -506,7 +752,8 @@ impl Parser<'_> {
     }
 
     fn function(&mut self, r#type: FunctionType) {
-        self.compiler.push_new(r#type, &self.previous);
+        let name = self.intern(self.previous.lexeme.to_string());
+        self.compiler.push_new(r#type, name);
         self.begin_scope();
 
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
@@ -531,9 +778,75 @@ impl Parser<'_> {
         self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
         self.block();
 
+        let upvalues = mem::take(&mut self.compiler.upvalues);
         let function = self.end_compiler();
-        let constant = self.make_constant(Value::Obj(Obj::Function(function.into())));
-        self.emit_bytes(OpCode::Closure.into(), constant);
+        let constant = self.make_constant(Value::obj(Obj::Function(function)));
+        self.emit_constant_op(OpCode::Closure, OpCode::ClosureLong, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local.into(), upvalue.index);
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let constant = self.identifier_constant(&self.previous.clone());
+
+        let r#type = if &*self.previous.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(r#type);
+        self.emit_constant_op(OpCode::Method, OpCode::MethodLong, constant);
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+        let global = if self.compiler.scope_depth > 0 { 0 } else { self.global_slot(&class_name) };
+
+        self.emit_constant_op(OpCode::Class, OpCode::ClassLong, name_constant);
+        self.define_variable(global);
+
+        let class_compiler = ClassCompiler {
+            enclosing: self.class_compiler.take(),
+            has_superclass: false,
+        };
+        self.class_compiler = Some(class_compiler.into());
+
+        if self.matches(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if self.identifiers_equal(&class_name, &self.previous) {
+                self.error("A class can't inherit from itself.");
+            }
+
+            self.begin_scope();
+            self.add_local(&synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name.clone(), false);
+            self.emit_byte(OpCode::Inherit.into());
+            self.class_compiler.as_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop.into());
+
+        let class_compiler = self.class_compiler.take().unwrap();
+        if class_compiler.has_superclass {
+            self.end_scope();
+        }
+        self.class_compiler = class_compiler.enclosing;
     }
 
     fn fun_declaration(&mut self) {
@@ -641,6 +954,10 @@ impl Parser<'_> {
         if self.matches(TokenType::Semicolon) {
             self.emit_return();
         } else {
+            if self.compiler.r#type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after return value.");
             self.emit_byte(OpCode::Return.into());
@@ -680,13 +997,15 @@ impl Parser<'_> {
                 | TokenType::Return => return,
                 _ => (),
             }
-        }
 
-        self.advance();
+            self.advance();
+        }
     }
 
     fn declaration(&mut self) {
-        if self.matches(TokenType::Fun) {
+        if self.matches(TokenType::Class) {
+            self.class_declaration();
+        } else if self.matches(TokenType::Fun) {
             self.fun_declaration();
         } else if self.matches(TokenType::Var) {
             self.var_declaration();
@@ -726,33 +1045,147 @@ impl Parser<'_> {
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
 
-        while self.compiler.locals.len() > 0 && self.compiler.locals.last().unwrap().depth > self.compiler.scope_depth.try_into().unwrap() {
-            self.emit_byte(OpCode::Pop.into());
+        while let Some(local) = self.compiler.locals.last() {
+            if local.depth <= Some(self.compiler.scope_depth) {
+                break;
+            }
+
+            if local.is_captured {
+                self.emit_byte(OpCode::CloseUpvalue.into());
+            } else {
+                self.emit_byte(OpCode::Pop.into());
+            }
             self.compiler.locals.pop();
         }
     }
 
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Nil.into());
+        if self.compiler.r#type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal.into(), 0);
+        } else {
+            self.emit_byte(OpCode::Nil.into());
+        }
         self.emit_byte(OpCode::Return.into());
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.current_chunk().add_constant(value);
-        if constant > u8::MAX.into() {
-            self.error("Too many constant in one chunk.");
-            return 0;
-        }
-
-        return constant as u8;
+    fn make_constant(&mut self, value: Value) -> usize {
+        self.current_chunk().add_constant(value).unwrap_or_else(|| {
+            self.error("Too many constants in one chunk.");
+            0
+        })
     }
 
     fn emit_constant(&mut self, value: Value) {
+        let start = self.current_chunk().code.len();
         let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant.into(), constant);
+        self.emit_constant_op(OpCode::Constant, OpCode::ConstantLong, constant);
+        self.compiler.last_constant = Some(ConstantLoad {
+            start,
+            end: self.current_chunk().code.len(),
+            value,
+            constant: Some(constant),
+        });
+    }
+
+    /// Emits the shortest load of `value`, which must be a literal or a string.
+    fn emit_value(&mut self, value: Value) {
+        let op = match value.as_bool() {
+            _ if value.is_nil() => OpCode::Nil,
+            Some(false) => OpCode::False,
+            Some(true) => OpCode::True,
+            None => return self.emit_constant(value),
+        };
+
+        let start = self.current_chunk().code.len();
+        self.emit_byte(op.into());
+        self.compiler.last_constant = Some(ConstantLoad {
+            start,
+            end: start + 1,
+            value,
+            constant: None,
+        });
+    }
+
+    /// The constant load that ends the code so far, if the expression just compiled is one.
+    fn constant_operand(&mut self) -> Option<ConstantLoad> {
+        let len = self.current_chunk().code.len();
+        self.compiler.last_constant.filter(|load| load.end == len)
+    }
+
+    /// Computes a binary operator on constants, unless it has to be left to runtime because it
+    /// would fail or divide by zero.
+    fn fold_binary(&mut self, operator_type: TokenType, a: Value, b: Value) -> Option<Value> {
+        let value = match (operator_type, a.as_number().zip(b.as_number())) {
+            (TokenType::EqualEqual, _) => Value::bool(a == b),
+            (TokenType::BangEqual, _) => Value::bool(a != b),
+            (TokenType::Plus, None) => match (a.as_obj(), b.as_obj()) {
+                (Some(Obj::String(a)), Some(Obj::String(b))) => {
+                    Value::obj(Obj::String(self.intern(a.string.clone() + &b.string)))
+                }
+                _ => return None,
+            },
+            (_, Some((a, b))) => match operator_type {
+                // Like the VM, `>=` means `!(a < b)` and `<=` means `!(a > b)`, which NaN satisfies.
+                TokenType::Greater => Value::bool(a > b),
+                TokenType::GreaterEqual => Value::bool(a.partial_cmp(&b) != Some(Ordering::Less)),
+                TokenType::Less => Value::bool(a < b),
+                TokenType::LessEqual => Value::bool(a.partial_cmp(&b) != Some(Ordering::Greater)),
+                TokenType::Plus => Value::number(a + b),
+                TokenType::Minus => Value::number(a - b),
+                TokenType::Star => Value::number(a * b),
+                TokenType::Slash if b != 0.0 => Value::number(a / b),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Replaces the loads of `operands`, which end the code, with a load of their folded `value`.
+    fn replace_with_constant(&mut self, operands: &[ConstantLoad], value: Value) {
+        self.current_chunk().truncate(operands[0].start);
+        // Give back the operands' constants too, when nothing has been added after them.
+        for operand in operands.iter().rev() {
+            let constants = &mut self.current_chunk().constants;
+            if operand.constant.is_some_and(|constant| constant + 1 == constants.len()) {
+                constants.pop();
+            }
+        }
+        self.emit_value(value);
+    }
+
+    /// Emits `op`, or `long_op` if the constant index doesn't fit in a byte.
+    fn emit_constant_op(&mut self, op: OpCode, long_op: OpCode, constant: usize) {
+        self.emit_constant_op_at(op, long_op, constant, self.previous.position());
+    }
+
+    fn emit_constant_op_at(&mut self, op: OpCode, long_op: OpCode, constant: usize, position: Position) {
+        if constant <= u8::MAX.into() {
+            self.emit_operand_at(op, constant, position);
+        } else {
+            self.emit_operand_at(long_op, constant, position);
+        }
+    }
+
+    fn emit_operand(&mut self, op: OpCode, operand: usize) {
+        self.emit_operand_at(op, operand, self.previous.position());
+    }
+
+    fn emit_operand_at(&mut self, op: OpCode, operand: usize, position: Position) {
+        self.emit_byte_at(op.into(), position);
+        if op.has_long_operand() {
+            self.emit_byte_at(((operand >> 16) & 0xff) as u8, position);
+            self.emit_byte_at(((operand >> 8) & 0xff) as u8, position);
+            self.emit_byte_at((operand & 0xff) as u8, position);
+        } else {
+            self.emit_byte_at(operand as u8, position);
+        }
     }
 
     fn patch_jump(&mut self, offset: usize) {
+        // The code after the jump may now run without whatever constant load ends it.
+        self.compiler.last_constant = None;
+
         // -2 to adjust for the bytecode for the jump offset itself.
         let jump = self.current_chunk().code.len() - offset - 2;
 
@@ -801,17 +1234,22 @@ impl Parser<'_> {
             return;
         }
         self.panic_mode = true;
-        eprint!("[{}:{}] Error", &token.line, token.col as usize - &token.lexeme.len());
 
-        if token.r#type == TokenType::Eof {
-            eprint!(" at end");
+        let location = if token.r#type == TokenType::Eof {
+            Some(Location::End)
         } else if token.r#type == TokenType::Error {
-            // Nothing.
+            None
         } else {
-            eprint!(" at '{}'", token.lexeme);
-        }
+            Some(Location::Token(token.lexeme.to_string()))
+        };
 
-        eprintln!(": {}", message);
+        self.diagnostics.push(Diagnostic {
+            line: token.line,
+            column: token.position().column as usize,
+            span: token.span,
+            location,
+            message: message.to_string(),
+        });
         self.had_error = true;
     }
 }
@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::diagnostic::{self, Source, Span};
+
+/// Where in the source a compile error was reported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// At the token with this lexeme.
+    Token(String),
+    /// At the end of the source.
+    End,
+}
+
+/// A compile error, as reported by the parser.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: u32,
+    pub column: usize,
+    /// The offending token, or for errors from the scanner, the text it couldn't make sense of.
+    pub span: Span,
+    /// `None` for errors from the scanner, whose message already says what went wrong.
+    pub location: Option<Location>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Writes the error with the line of `source` it points at, the token underlined.
+    pub fn render(&self, out: &mut dyn Write, source: &Source, color: bool) -> io::Result<()> {
+        diagnostic::render(out, source, self.span, &self.location_text(), &self.message, color)
+    }
+
+    fn location_text(&self) -> String {
+        match &self.location {
+            Some(Location::Token(lexeme)) => format!(" at '{}'", lexeme),
+            Some(Location::End) => " at end".to_string(),
+            None => String::new(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}:{}] Error{}: {}", self.line, self.column, self.location_text(), self.message)
+    }
+}
+
+impl Error for Diagnostic {}
+
+/// One call frame of a [`RuntimeError`]'s stack trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// Name of the function, or `None` for the top-level script.
+    pub function: Option<String>,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}:{}] in ", self.line, self.column)?;
+        match &self.function {
+            Some(name) => write!(f, "{}()", name),
+            None => write!(f, "script"),
+        }
+    }
+}
+
+/// An error raised while running a program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// Line and column of the instruction that failed.
+    pub line: u32,
+    pub column: u32,
+    /// The call stack when the error happened, innermost frame first.
+    pub trace: Vec<TraceFrame>,
+    /// Whether the script was stopped through an [`InterruptHandle`] rather than failing.
+    ///
+    /// [`InterruptHandle`]: super::interrupt::InterruptHandle
+    pub interrupted: bool,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for RuntimeError {}
+
+pub type RuntimeResult<T> = Result<T, RuntimeError>;
+
+/// An error found while loading a compiled `.loxc` file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file doesn't start with the `.loxc` magic number.
+    BadMagic,
+    /// The file was written for another version of the format.
+    UnsupportedVersion(u16),
+    /// The file ends in the middle of a function.
+    UnexpectedEnd,
+    /// The file is well formed, but what it holds isn't valid bytecode.
+    Invalid(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "Not a compiled Lox file."),
+            LoadError::UnsupportedVersion(version) => write!(f, "Unsupported bytecode version {}.", version),
+            LoadError::UnexpectedEnd => write!(f, "Unexpected end of bytecode."),
+            LoadError::Invalid(message) => write!(f, "Invalid bytecode: {}", message),
+        }
+    }
+}
+
+impl Error for LoadError {}
@@ -0,0 +1,75 @@
+//! Stopping a running VM from another thread, or from a signal handler.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Stops the script running in the VM it came from, see [`VM::interrupt_handle`].
+///
+/// Handles can be cloned and sent to other threads, and all of them stop the same VM.
+///
+/// [`VM::interrupt_handle`]: super::vm::VM::interrupt_handle
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    /// Asks the VM to stop. The script unwinds at its next call or backward jump with an
+    /// "Interrupted." runtime error. A request made while nothing is running is dropped when
+    /// the next script or call from the host starts, so the host never has to clear it.
+    pub fn interrupt(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Clears the request, returning whether there was one.
+    pub(super) fn take(&self) -> bool {
+        // Loading first keeps the common case free of a read-modify-write.
+        self.requested.load(Ordering::Relaxed) && self.requested.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[cfg(unix)]
+pub use sigint::interrupt_on_sigint;
+
+#[cfg(unix)]
+mod sigint {
+    use std::ffi::c_int;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+    use std::sync::Arc;
+
+    use super::InterruptHandle;
+
+    const SIGINT: c_int = 2;
+    const SIG_ERR: usize = usize::MAX;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    /// The flag of the handle SIGINT interrupts, or null before there is one.
+    static TARGET: AtomicPtr<AtomicBool> = AtomicPtr::new(std::ptr::null_mut());
+
+    extern "C" fn on_sigint(_signum: c_int) {
+        let target = TARGET.load(Ordering::Acquire);
+        if !target.is_null() {
+            // SAFETY: Flags stored in `TARGET` are leaked, so they are never freed.
+            unsafe { (*target).store(true, Ordering::Relaxed) };
+        }
+    }
+
+    /// Makes Ctrl-C interrupt the VM `handle` came from instead of killing the process.
+    ///
+    /// Only one VM is interrupted at a time: calling this again moves Ctrl-C to the new handle.
+    pub fn interrupt_on_sigint(handle: &InterruptHandle) -> io::Result<()> {
+        // The flag is leaked, since the handler may still be using it after another replaces it.
+        let flag = Arc::into_raw(Arc::clone(&handle.requested)).cast_mut();
+        TARGET.store(flag, Ordering::Release);
+
+        // SAFETY: The handler only touches atomics, which is all a signal handler may do.
+        if unsafe { signal(SIGINT, on_sigint) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,565 @@
+//! The `.loxc` format, for saving compiled bytecode and running it later without recompiling.
+//!
+//! A file is the magic number, a little-endian `u16` version, the names of the global slots and the
+//! top-level function. A function is its arity, upvalue count, name, code, position table,
+//! constants and number of inline caches, where constants that are functions are written in place. All
+//! integers are little-endian.
+//!
+//! Global slots are handed out by the VM that compiled the script, so loading maps each name to
+//! the loading VM's slot and rewrites the operands that refer to it, switching to the long form of
+//! an instruction when its new slot doesn't fit in a byte. Names the loading VM doesn't know yet
+//! are only added once the whole file has loaded.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::chunk::{Chunk, LineRun, OpCode, Position, MAX_LONG_CONSTANT};
+use super::optimizer;
+use super::error::LoadError;
+use super::memory::{Gc, Heap};
+use super::object::{Function, Obj, StringObj};
+use super::value::Value;
+use super::vm::VM;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+/// Bumped whenever the layout or the opcode numbering changes.
+pub const VERSION: u16 = 7;
+
+/// How deeply functions can be nested in a file, counting the script itself. Loading recurses
+/// into nested functions, so this keeps a malicious file from overflowing the stack.
+pub const MAX_DEPTH: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+/// Serializes a script compiled on `vm`.
+pub fn write(function: &Function, vm: &VM) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    let names: Vec<_> = vm.global_names().collect();
+    write_u32(&mut bytes, names.len() as u32);
+    for name in names {
+        write_string(&mut bytes, &name.string);
+    }
+    write_function(&mut bytes, function);
+    bytes
+}
+
+fn write_function(bytes: &mut Vec<u8>, function: &Function) {
+    bytes.push(function.arity);
+    write_u32(bytes, function.upvalue_count as u32);
+    match function.name {
+        Some(name) => {
+            bytes.push(1);
+            write_string(bytes, &name.string);
+        }
+        None => bytes.push(0),
+    }
+
+    let chunk = &function.chunk;
+    write_u32(bytes, chunk.code.len() as u32);
+    bytes.extend_from_slice(&chunk.code);
+
+    write_u32(bytes, chunk.lines.len() as u32);
+    for run in &chunk.lines {
+        write_u32(bytes, run.start);
+        write_u32(bytes, run.position.line);
+        write_u32(bytes, run.position.column);
+    }
+
+    write_u32(bytes, chunk.constants.len() as u32);
+    for constant in &chunk.constants {
+        if constant.is_nil() {
+            bytes.push(TAG_NIL);
+        } else if let Some(boolean) = constant.as_bool() {
+            bytes.push(if boolean { TAG_TRUE } else { TAG_FALSE });
+        } else if let Some(number) = constant.as_number() {
+            bytes.push(TAG_NUMBER);
+            bytes.extend_from_slice(&number.to_le_bytes());
+        } else {
+            match constant.as_obj() {
+                Some(Obj::String(string)) => {
+                    bytes.push(TAG_STRING);
+                    write_string(bytes, &string.string);
+                }
+                Some(Obj::Function(function)) => {
+                    bytes.push(TAG_FUNCTION);
+                    write_function(bytes, &function);
+                }
+                _ => unreachable!("The compiler never emits {} as a constant.", constant),
+            }
+        }
+    }
+
+    // Only the number of inline caches, they start out empty.
+    write_u32(bytes, chunk.caches.len() as u32);
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    write_u32(bytes, string.len() as u32);
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+/// Loads a script written by [`write`], checking that every instruction is well formed.
+///
+/// Like a freshly compiled function, the result isn't rooted, so it has to be run before the VM
+/// allocates again.
+pub fn read(bytes: &[u8], vm: &mut VM) -> Result<Gc<Function>, LoadError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(LoadError::BadMagic);
+    }
+
+    let mut reader = Reader {
+        vm,
+        bytes,
+        position: MAGIC.len(),
+        roots: Vec::new(),
+        globals: Vec::new(),
+        depth: 0,
+    };
+    let version = u16::from_le_bytes(reader.read_array()?);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    // New names get the slots the VM will hand out when they're added, in the same order.
+    let global_count = reader.read_u32()?;
+    let mut new_globals = HashMap::new();
+    let mut new_names = Vec::new();
+    for _ in 0..global_count {
+        let name = reader.read_string()?;
+        let slot = match reader.vm.find_global_slot(name) {
+            Some(slot) => slot,
+            None => *new_globals.entry(name).or_insert_with(|| {
+                new_names.push(name);
+                reader.vm.global_count() + new_names.len() - 1
+            }),
+        };
+        reader.globals.push(slot);
+    }
+    if reader.globals.iter().any(|slot| *slot > MAX_LONG_CONSTANT) {
+        return Err(invalid("too many globals to relink"));
+    }
+
+    let function = reader.read_function()?;
+    if function.upvalue_count != 0 {
+        return Err(invalid("the script can't capture upvalues"));
+    }
+    if reader.position != bytes.len() {
+        return Err(invalid("trailing bytes after the script"));
+    }
+
+    for name in new_names {
+        let slot = reader.vm.global_slot(name);
+        debug_assert_eq!(Some(&slot), new_globals.get(&name));
+    }
+    Ok(function)
+}
+
+fn invalid(message: &str) -> LoadError {
+    LoadError::Invalid(message.to_string())
+}
+
+struct Reader<'a> {
+    vm: &'a mut VM,
+    bytes: &'a [u8],
+    position: usize,
+    /// Everything allocated so far, kept alive until the whole script is loaded.
+    roots: Vec<Value>,
+    /// The loading VM's slot for each global slot in the file.
+    globals: Vec<usize>,
+    /// How many functions enclose the one being read.
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn read_bytes(&mut self, length: usize) -> Result<&[u8], LoadError> {
+        let end = self.position.checked_add(length).ok_or(LoadError::UnexpectedEnd)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(LoadError::UnexpectedEnd)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_string(&mut self) -> Result<Gc<StringObj>, LoadError> {
+        let length = self.read_u32()? as usize;
+        let string = std::str::from_utf8(self.read_bytes(length)?)
+            .map_err(|_| invalid("string constant is not valid UTF-8"))?
+            .to_string();
+
+        let Reader { vm, roots, .. } = self;
+        let string = vm.intern_with_roots(string, |heap| mark(heap, roots));
+        self.roots.push(Value::obj(Obj::String(string)));
+        Ok(string)
+    }
+
+    fn read_function(&mut self) -> Result<Gc<Function>, LoadError> {
+        if self.depth == MAX_DEPTH {
+            return Err(invalid("functions nested too deeply"));
+        }
+        self.depth += 1;
+        let function = self.read_function_body();
+        self.depth -= 1;
+        function
+    }
+
+    fn read_function_body(&mut self) -> Result<Gc<Function>, LoadError> {
+        let mut function = Function::new();
+        function.arity = self.read_u8()?;
+        function.upvalue_count = self.read_u32()? as usize;
+        // Upvalues are addressed by a byte operand.
+        if function.upvalue_count > u8::MAX as usize + 1 {
+            return Err(invalid("too many upvalues"));
+        }
+        function.name = match self.read_u8()? {
+            0 => None,
+            1 => Some(self.read_string()?),
+            _ => return Err(invalid("bad function name marker")),
+        };
+
+        let mut chunk = Chunk::new();
+        let code_length = self.read_u32()? as usize;
+        chunk.code = self.read_bytes(code_length)?.to_vec();
+
+        let run_count = self.read_u32()?;
+        for _ in 0..run_count {
+            let start = self.read_u32()?;
+            let line = self.read_u32()?;
+            let column = self.read_u32()?;
+            // Runs must start at the beginning of the code and stay sorted for the binary search.
+            let expected = match chunk.lines.last() {
+                Some(last) => start > last.start && (start as usize) < chunk.code.len(),
+                None => start == 0,
+            };
+            if !expected {
+                return Err(invalid("position table doesn't match the code"));
+            }
+            chunk.lines.push(LineRun { start, position: Position { line, column } });
+        }
+        if chunk.lines.is_empty() != chunk.code.is_empty() {
+            return Err(invalid("position table doesn't match the code"));
+        }
+
+        let constant_count = self.read_u32()?;
+        for _ in 0..constant_count {
+            let constant = match self.read_u8()? {
+                TAG_NIL => Value::NIL,
+                TAG_FALSE => Value::bool(false),
+                TAG_TRUE => Value::bool(true),
+                TAG_NUMBER => Value::number(f64::from_le_bytes(self.read_array()?)),
+                TAG_STRING => Value::obj(Obj::String(self.read_string()?)),
+                TAG_FUNCTION => Value::obj(Obj::Function(self.read_function()?)),
+                _ => return Err(invalid("unknown constant tag")),
+            };
+            chunk.constants.push(constant);
+        }
+
+        // Caches are addressed by a two-byte operand.
+        let cache_count = self.read_u32()? as usize;
+        if cache_count > u16::MAX as usize + 1 {
+            return Err(invalid("too many inline caches"));
+        }
+        chunk.caches = vec![Default::default(); cache_count];
+
+        function.chunk = chunk;
+        validate(&function, self.globals.len())?;
+        self.relink_globals(&mut function.chunk)?;
+
+        let Reader { vm, roots, .. } = self;
+        let function = vm.allocate_with_roots(function, |heap| mark(heap, roots));
+        self.roots.push(Value::obj(Obj::Function(function)));
+        Ok(function)
+    }
+
+    /// Rewrites the operand of every global instruction from the file's slot to the VM's.
+    fn relink_globals(&self, chunk: &mut Chunk) -> Result<(), LoadError> {
+        if !optimizer::relink_globals(chunk, |slot| self.globals[slot]) {
+            return Err(invalid("function is too long to relink its globals"));
+        }
+        Ok(())
+    }
+}
+
+fn mark(heap: &mut Heap, roots: &[Value]) {
+    for value in roots {
+        heap.mark_value(*value);
+    }
+}
+
+/// How an instruction changes the stack, and where execution goes next.
+struct Effect {
+    /// How many values the instruction needs on the stack.
+    needs: usize,
+    /// How many values it leaves pushed (or popped) afterwards.
+    delta: isize,
+    /// The deepest local slot it touches, relative to the frame.
+    local: Option<usize>,
+    /// Local slots it captures as upvalues.
+    captures: Vec<usize>,
+    /// Whether it closes the upvalue for the slot it pops, like `OpCloseUpvalue`.
+    closes: bool,
+    /// Whether execution can continue with the next instruction.
+    falls_through: bool,
+    jump: Option<usize>,
+}
+
+/// Checks that every operand is in bounds, so the VM can run the function without further checks.
+///
+/// Besides decoding each instruction, this follows every path through the function to check that
+/// the stack depth agrees wherever paths meet, that locals and call arguments are within the stack,
+/// that captured locals are closed before they're popped, and that no path runs off the end of
+/// the code.
+fn validate(function: &Function, global_count: usize) -> Result<(), LoadError> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let upvalue_count = function.upvalue_count;
+
+    let operand = |offset: usize, width: usize| -> Result<usize, LoadError> {
+        if offset + width > code.len() {
+            return Err(invalid("instruction runs past the end of the code"));
+        }
+        Ok(match width {
+            1 => code[offset] as usize,
+            2 => (code[offset] as usize) << 8 | code[offset + 1] as usize,
+            _ => chunk.read_long(offset),
+        })
+    };
+    let constant = |index: usize| -> Result<Value, LoadError> {
+        chunk.constants.get(index).copied().ok_or_else(|| invalid("constant index out of bounds"))
+    };
+    let cache = |index: usize| -> Result<(), LoadError> {
+        if index >= chunk.caches.len() {
+            return Err(invalid("inline cache index out of bounds"));
+        }
+        Ok(())
+    };
+    let string_constant = |index: usize| -> Result<(), LoadError> {
+        match constant(index)?.as_obj() {
+            Some(Obj::String(_)) => Ok(()),
+            _ => Err(invalid("name operand is not a string")),
+        }
+    };
+
+    let mut effects = Vec::new();
+    let mut starts = vec![None; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        starts[offset] = Some(effects.len());
+        let op = OpCode::try_from(code[offset]).map_err(|_| invalid("unknown opcode"))?;
+        let width = if op.has_long_operand() { 3 } else { 1 };
+        let mut effect = Effect {
+            needs: 0,
+            delta: 0,
+            local: None,
+            captures: Vec::new(),
+            closes: matches!(op, OpCode::CloseUpvalue),
+            falls_through: true,
+            jump: None,
+        };
+        offset += 1;
+
+        let (needs, delta) = match op {
+            OpCode::Nil | OpCode::True | OpCode::False => (0, 1),
+            OpCode::Pop | OpCode::Print | OpCode::CloseUpvalue => (1, -1),
+            OpCode::Not | OpCode::Negate => (1, 0),
+            OpCode::Equal | OpCode::NotEqual | OpCode::Greater | OpCode::Less | OpCode::GreaterEqual
+            | OpCode::LessEqual | OpCode::Add | OpCode::Subtract
+            | OpCode::Multiply | OpCode::Divide | OpCode::Inherit => (2, -1),
+            OpCode::Return => {
+                effect.falls_through = false;
+                (1, -1)
+            }
+            OpCode::Constant | OpCode::ConstantLong => {
+                constant(operand(offset, width)?)?;
+                offset += width;
+                (0, 1)
+            }
+            OpCode::GetGlobal | OpCode::GetGlobalLong | OpCode::DefineGlobal | OpCode::DefineGlobalLong
+            | OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                if operand(offset, width)? >= global_count {
+                    return Err(invalid("global slot out of bounds"));
+                }
+                offset += width;
+                match op {
+                    OpCode::GetGlobal | OpCode::GetGlobalLong => (0, 1),
+                    OpCode::DefineGlobal | OpCode::DefineGlobalLong => (1, -1),
+                    _ => (1, 0),
+                }
+            }
+            OpCode::GetProperty | OpCode::GetPropertyLong | OpCode::SetProperty | OpCode::SetPropertyLong
+            | OpCode::GetSuper | OpCode::GetSuperLong | OpCode::Class | OpCode::ClassLong | OpCode::Method
+            | OpCode::MethodLong => {
+                string_constant(operand(offset, width)?)?;
+                offset += width;
+                if matches!(
+                    op,
+                    OpCode::GetProperty | OpCode::GetPropertyLong | OpCode::SetProperty | OpCode::SetPropertyLong
+                ) {
+                    cache(operand(offset, 2)?)?;
+                    offset += 2;
+                }
+                match op {
+                    OpCode::Class | OpCode::ClassLong => (0, 1),
+                    OpCode::GetProperty | OpCode::GetPropertyLong => (1, 0),
+                    _ => (2, -1),
+                }
+            }
+            OpCode::GetLocal | OpCode::SetLocal => {
+                effect.local = Some(operand(offset, 1)?);
+                offset += 1;
+                if matches!(op, OpCode::GetLocal) { (0, 1) } else { (1, 0) }
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                if operand(offset, 1)? >= upvalue_count {
+                    return Err(invalid("upvalue index out of bounds"));
+                }
+                offset += 1;
+                if matches!(op, OpCode::GetUpvalue) { (0, 1) } else { (1, 0) }
+            }
+            OpCode::Call => {
+                let arg_count = operand(offset, 1)?;
+                offset += 1;
+                (arg_count + 1, -(arg_count as isize))
+            }
+            OpCode::Invoke | OpCode::InvokeLong | OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                string_constant(operand(offset, width)?)?;
+                let arg_count = operand(offset + width, 1)?;
+                offset += width + 1;
+                if matches!(op, OpCode::Invoke | OpCode::InvokeLong) {
+                    cache(operand(offset, 2)?)?;
+                    offset += 2;
+                    (arg_count + 1, -(arg_count as isize))
+                } else {
+                    // The superclass sits on top of the arguments.
+                    (arg_count + 2, -(arg_count as isize) - 1)
+                }
+            }
+            OpCode::AddLocalConstant | OpCode::SubtractLocalConstant => {
+                effect.local = Some(operand(offset, 1)?);
+                if constant(operand(offset + 1, 1)?)?.as_number().is_none() {
+                    return Err(invalid("arithmetic operand is not a number"));
+                }
+                offset += 2;
+                (0, 1)
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue | OpCode::Loop
+            | OpCode::LessJumpIfFalse | OpCode::GreaterJumpIfFalse => {
+                let jump = operand(offset, 2)?;
+                offset += 2;
+                let target = if matches!(op, OpCode::Loop) {
+                    offset.checked_sub(jump)
+                } else {
+                    Some(offset + jump)
+                };
+                effect.jump = Some(target.ok_or_else(|| invalid("jump target out of bounds"))?);
+                match op {
+                    OpCode::Jump | OpCode::Loop => {
+                        effect.falls_through = false;
+                        (0, 0)
+                    }
+                    OpCode::JumpIfFalse | OpCode::JumpIfTrue => (1, 0),
+                    // The comparison is popped whichever way it goes.
+                    _ => (2, -2),
+                }
+            }
+            OpCode::Closure | OpCode::ClosureLong => {
+                let closure = match constant(operand(offset, width)?)?.as_obj() {
+                    Some(Obj::Function(closure)) => closure,
+                    _ => return Err(invalid("closure operand is not a function")),
+                };
+                offset += width;
+                for _ in 0..closure.upvalue_count {
+                    let is_local = operand(offset, 1)?;
+                    let index = operand(offset + 1, 1)?;
+                    match is_local {
+                        1 => effect.captures.push(index),
+                        0 if index < upvalue_count => {}
+                        _ => return Err(invalid("captured upvalue out of bounds")),
+                    }
+                    offset += 2;
+                }
+                (0, 1)
+            }
+        };
+        effect.needs = needs;
+        effect.delta = delta;
+        effects.push(effect);
+    }
+
+    if effects.is_empty() {
+        return Err(invalid("function has no code"));
+    }
+
+    // Follow every path from the start, where the stack holds the callee and its arguments. Since
+    // a local may be captured on some paths only, paths meet with the union of what they captured.
+    let mut states: Vec<Option<(usize, BTreeSet<usize>)>> = vec![None; effects.len()];
+    let mut pending = vec![(0, function.arity as usize + 1, BTreeSet::new())];
+    while let Some((index, depth, mut captured)) = pending.pop() {
+        if let Some((known_depth, known_captured)) = &mut states[index] {
+            if *known_depth != depth {
+                return Err(invalid("stack depth differs between paths"));
+            }
+            if captured.is_subset(known_captured) {
+                continue;
+            }
+            known_captured.extend(&captured);
+            captured = known_captured.clone();
+        } else {
+            states[index] = Some((depth, captured.clone()));
+        }
+
+        let effect = &effects[index];
+        if depth < effect.needs || effect.local.is_some_and(|slot| slot >= depth) {
+            return Err(invalid("instruction reaches below its frame"));
+        }
+        // A function can capture itself, in the slot the new closure is about to take.
+        if effect.captures.iter().any(|slot| *slot > depth) {
+            return Err(invalid("captured local is beyond the stack"));
+        }
+        // Returning closes every upvalue, anything else may only pop a captured local by closing it.
+        // Operands count as popped even when the result goes back in their place, since failing
+        // instructions leave them popped.
+        let returns = !effect.falls_through && effect.jump.is_none();
+        let lowest = depth - effect.needs;
+        let popped = captured.split_off(&lowest);
+        let closed = effect.closes && popped.len() == 1 && popped.contains(&lowest);
+        if !returns && !popped.is_empty() && !closed {
+            return Err(invalid("captured local popped without being closed"));
+        }
+        let depth = depth.checked_add_signed(effect.delta).unwrap();
+        captured.extend(&effect.captures);
+
+        if let Some(target) = effect.jump {
+            let target = starts.get(target).copied().flatten();
+            let target = target.ok_or_else(|| invalid("jump target is not an instruction"))?;
+            pending.push((target, depth, captured.clone()));
+        }
+        if effect.falls_through {
+            if index + 1 == effects.len() {
+                return Err(invalid("code runs past the end of the function"));
+            }
+            pending.push((index + 1, depth, captured));
+        }
+    }
+    Ok(())
+}
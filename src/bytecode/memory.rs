@@ -0,0 +1,235 @@
+use std::cell::Cell;
+use std::fmt;
+use std::hash;
+use std::mem;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use super::object::{Obj, Object};
+use super::value::Value;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+struct GcBox<T> {
+    is_marked: Cell<bool>,
+    size: Cell<usize>,
+    value: T,
+}
+
+/// A handle to an object owned by a [`Heap`].
+///
+/// Handles are cheap to copy and compare by identity. They stay valid for as long as the object
+/// is reachable from the VM's roots; the collector frees everything else.
+pub struct Gc<T> {
+    pointer: NonNull<GcBox<T>>,
+}
+
+impl<T> Gc<T> {
+    fn inner(&self) -> &GcBox<T> {
+        // SAFETY: The heap only frees an object once it is unreachable, and an unreachable object
+        // has no handles left to dereference.
+        unsafe { self.pointer.as_ref() }
+    }
+
+    pub fn is_marked(&self) -> bool {
+        self.inner().is_marked.get()
+    }
+
+    pub fn ptr_eq(a: &Gc<T>, b: &Gc<T>) -> bool {
+        a.pointer == b.pointer
+    }
+
+    /// The address of the object, which is always aligned to at least 8 bytes.
+    #[cfg(feature = "nan-boxing")]
+    pub(super) fn addr(self) -> usize {
+        self.pointer.as_ptr() as usize
+    }
+
+    /// # Safety
+    ///
+    /// `addr` must come from [`Gc::addr`] on a handle of the same type.
+    #[cfg(feature = "nan-boxing")]
+    pub(super) unsafe fn from_addr(addr: usize) -> Self {
+        Gc { pointer: NonNull::new_unchecked(addr as *mut GcBox<T>) }
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().value
+    }
+}
+
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Gc::ptr_eq(self, other)
+    }
+}
+
+impl<T> Eq for Gc<T> {}
+
+impl<T> hash::Hash for Gc<T> {
+    fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
+        self.pointer.hash(hasher);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", **self)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", **self)
+    }
+}
+
+/// Owner of every object the VM allocates, collected with a mark-sweep collector.
+///
+/// The heap doesn't know the VM's roots, so it never collects on its own. Whoever allocates
+/// checks [`Heap::should_collect`], marks its roots, calls [`Heap::trace_references`] and, after
+/// dropping any weak references to unmarked objects, [`Heap::sweep`].
+pub struct Heap {
+    objects: Vec<Obj>,
+    gray_stack: Vec<Obj>,
+    bytes_allocated: usize,
+    next_gc: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Default::default(),
+            gray_stack: Default::default(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+        }
+    }
+
+    /// The bytes `value` will account for once it's allocated.
+    pub fn size_of<T: Object>(value: &T) -> usize {
+        mem::size_of::<GcBox<T>>() + value.extra_size()
+    }
+
+    pub fn alloc<T: Object>(&mut self, value: T) -> Gc<T> {
+        let size = Self::size_of(&value);
+        let boxed = Box::new(GcBox {
+            is_marked: Cell::new(false),
+            size: Cell::new(size),
+            value,
+        });
+        let gc = Gc {
+            pointer: NonNull::from(Box::leak(boxed)),
+        };
+
+        self.bytes_allocated += size;
+        self.objects.push(T::into_obj(gc));
+        gc
+    }
+
+    /// Accounts for `bytes` more that `gc` came to own after it was allocated, like a vector of
+    /// it that grew.
+    pub fn grow<T>(&mut self, gc: Gc<T>, bytes: usize) {
+        let size = &gc.inner().size;
+        size.set(size.get() + bytes);
+        self.bytes_allocated += bytes;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "stress-gc") || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(obj) = value.as_obj() {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: Obj) {
+        if obj.mark() {
+            self.gray_stack.push(obj);
+        }
+    }
+
+    /// Marks everything reachable from the marked roots.
+    pub fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            obj.trace(self);
+        }
+    }
+
+    /// Every marked object, which once the references have been traced is every object that
+    /// survives the sweep.
+    pub fn marked_objects(&self) -> impl Iterator<Item = Obj> + '_ {
+        self.objects.iter().copied().filter(|obj| obj.is_marked())
+    }
+
+    /// Frees every object that wasn't marked and clears the marks on the rest.
+    pub fn sweep(&mut self) {
+        let mut freed = 0;
+        self.objects.retain(|obj| {
+            if obj.unmark() {
+                true
+            } else {
+                // SAFETY: The object was not reached from any root, so no handle to it remains.
+                freed += unsafe { obj.free() };
+                false
+            }
+        });
+        self.bytes_allocated -= freed;
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for obj in self.objects.drain(..) {
+            // SAFETY: The heap is going away along with every handle into it.
+            unsafe { obj.free() };
+        }
+    }
+}
+
+/// Marks the object behind `gc`, returning `true` if it was not already marked.
+pub(super) fn mark<T>(gc: Gc<T>) -> bool {
+    !gc.inner().is_marked.replace(true)
+}
+
+/// Clears the mark on the object behind `gc`, returning whether it was marked.
+pub(super) fn unmark<T>(gc: Gc<T>) -> bool {
+    gc.inner().is_marked.replace(false)
+}
+
+/// Frees the object behind `gc`, returning the number of bytes it accounted for.
+///
+/// # Safety
+///
+/// No handle to the object may be used afterwards.
+pub(super) unsafe fn free<T>(gc: Gc<T>) -> usize {
+    let boxed = Box::from_raw(gc.pointer.as_ptr());
+    boxed.size.get()
+}
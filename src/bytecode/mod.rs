@@ -1,7 +1,14 @@
 pub mod chunk;
 pub mod compiler;
+pub mod error;
+pub mod interrupt;
+pub mod loxc;
+pub mod memory;
+pub mod native;
 pub mod object;
+pub mod optimizer;
 pub mod scanner;
+pub mod shape;
 pub mod table;
 pub mod value;
 pub mod vm;
@@ -11,11 +18,22 @@ use std::fs;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
 
-use vm::VM;
+use vm::{InterpretResult, VM};
+use crate::diagnostic::Source;
 
+/// Reads and runs lines until an empty one. Each line gets the fuel the VM had to begin with.
+///
+/// On Unix, Ctrl-C interrupts the line that's running and returns to the prompt. At the prompt
+/// itself it does nothing, and the next line runs as usual.
 pub fn repl(vm: &mut VM) {
     let input = io::stdin();
     let mut reader = BufReader::new(input);
+    let fuel = vm.fuel();
+
+    #[cfg(unix)]
+    if let Err(error) = interrupt::interrupt_on_sigint(&vm.interrupt_handle()) {
+        eprintln!("Ctrl-C will quit instead of interrupting: {}", error);
+    }
 
     println!("Lox Interactive REPL\n");
 
@@ -23,7 +41,9 @@ pub fn repl(vm: &mut VM) {
         print!("> ");
         io::stdout().flush().unwrap();
         let mut line = String::new();
-        reader.read_line(&mut line);
+        if reader.read_line(&mut line).is_err() {
+            break;
+        }
 
         if line.clone().trim().is_empty() {
             println!();
@@ -31,11 +51,43 @@ pub fn repl(vm: &mut VM) {
             println!();
             break;
         }
-        vm.interpret(&line);
+        vm.set_fuel(fuel);
+        let result = vm.interpret(&line);
+        vm.report_with_source(&result, &Source::new("<repl>", &line)).unwrap();
+        if let InterpretResult::OutOfFuel = result {
+            vm.reset_stack();
+        }
     }
 }
 
-pub fn run_file(vm: &mut VM, path: &str) -> io::Result<vm::InterpretResult> {
+/// Runs a script, either as source or as bytecode written by [`emit_bytecode`].
+///
+/// A `.loxc` file that fails to load is reported as an [`io::ErrorKind::InvalidData`] error.
+pub fn run_file(vm: &mut VM, path: &str) -> io::Result<InterpretResult> {
+    let bytes = fs::read(path)?;
+    if path.ends_with(".loxc") || bytes.starts_with(loxc::MAGIC) {
+        let function = loxc::read(&bytes, vm).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let result = vm.interpret_function(function);
+        vm.report(&result)?;
+        return Ok(result);
+    }
+
+    let source = String::from_utf8(bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let result = vm.interpret(&source);
+    vm.report_with_source(&result, &Source::new(path, &source))?;
+    Ok(result)
+}
+
+/// Compiles the script at `path` and writes its bytecode to `output` instead of running it.
+pub fn emit_bytecode(vm: &mut VM, path: &str, output: &str) -> io::Result<InterpretResult> {
     let source = fs::read_to_string(path)?;
-    Ok(vm.interpret(&source))
+    let result = match compiler::compile(&source, vm) {
+        Ok(function) => {
+            fs::write(output, loxc::write(&function, vm))?;
+            InterpretResult::Ok
+        }
+        Err(diagnostics) => InterpretResult::CompileError(diagnostics),
+    };
+    vm.report_with_source(&result, &Source::new(path, &source))?;
+    Ok(result)
 }
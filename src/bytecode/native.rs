@@ -0,0 +1,129 @@
+use super::object::{NativeFunction, Obj};
+use super::value::Value;
+use super::vm::{Handle, VM};
+
+/// Conversion from a Lox value into a Rust type, for reading the arguments of a native.
+pub trait FromLox: Sized {
+    fn from_lox(value: Value) -> Result<Self, String>;
+}
+
+/// Conversion from a Rust type into a Lox value, for returning results from a native.
+///
+/// Strings are allocated on the VM's heap, which is why the conversion needs the VM. The VM keeps
+/// what it converts alive until the native returns, or for the host, until the next call it makes
+/// has its arguments on the stack. The value mustn't be used after that.
+pub trait IntoLox {
+    fn into_lox(self, vm: &mut VM) -> Value;
+}
+
+impl IntoLox for Value {
+    fn into_lox(self, _vm: &mut VM) -> Value {
+        self
+    }
+}
+
+/// The value behind a handle, for passing something the host holds on to as an argument.
+impl IntoLox for &Handle {
+    fn into_lox(self, vm: &mut VM) -> Value {
+        let value = vm.handle_value(self);
+        vm.hold(value)
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: Value) -> Result<Self, String> {
+        value.as_number().ok_or_else(|| format!("Expected a number but got {}.", value))
+    }
+}
+
+impl IntoLox for f64 {
+    fn into_lox(self, _vm: &mut VM) -> Value {
+        Value::number(self)
+    }
+}
+
+impl FromLox for bool {
+    fn from_lox(value: Value) -> Result<Self, String> {
+        value.as_bool().ok_or_else(|| format!("Expected a boolean but got {}.", value))
+    }
+}
+
+impl IntoLox for bool {
+    fn into_lox(self, _vm: &mut VM) -> Value {
+        Value::bool(self)
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: Value) -> Result<Self, String> {
+        match value.as_obj() {
+            Some(Obj::String(string)) => Ok(string.string.clone()),
+            _ => Err(format!("Expected a string but got {}.", value)),
+        }
+    }
+}
+
+impl IntoLox for String {
+    fn into_lox(self, vm: &mut VM) -> Value {
+        let string = Value::obj(Obj::String(vm.intern(self)));
+        vm.hold(string)
+    }
+}
+
+impl IntoLox for &str {
+    fn into_lox(self, vm: &mut VM) -> Value {
+        self.to_string().into_lox(vm)
+    }
+}
+
+/// `nil` converts to `None`, anything else must convert to `T`.
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: Value) -> Result<Self, String> {
+        if value.is_nil() {
+            Ok(None)
+        } else {
+            T::from_lox(value).map(Some)
+        }
+    }
+}
+
+impl<T: IntoLox> IntoLox for Option<T> {
+    fn into_lox(self, vm: &mut VM) -> Value {
+        match self {
+            Some(value) => value.into_lox(vm),
+            None => Value::NIL,
+        }
+    }
+}
+
+impl IntoLox for () {
+    fn into_lox(self, _vm: &mut VM) -> Value {
+        Value::NIL
+    }
+}
+
+/// A named group of natives, registered with [`VM::register_module`].
+///
+/// Scripts see the module as a global object with one method per native, so a `get` native in a
+/// `config` module is called as `config.get("key")`.
+pub struct NativeModule {
+    pub(super) name: String,
+    pub(super) functions: Vec<NativeFunction>,
+}
+
+impl NativeModule {
+    pub fn new(name: &str) -> Self {
+        NativeModule {
+            name: name.to_string(),
+            functions: Vec::new(),
+        }
+    }
+
+    pub fn define<F>(&mut self, name: &str, arity: u8, function: F) -> &mut Self
+    where
+        F: FnMut(&mut VM, &[Value]) -> Result<Value, String> + 'static,
+    {
+        self.functions.push(NativeFunction::new(name.to_string(), arity, Box::new(function)));
+        self
+    }
+}
@@ -1,28 +1,144 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::fmt;
 use std::hash;
+use std::mem;
 
 use super::chunk::Chunk;
+use super::memory::{self, Gc, Heap};
+use super::shape::{InlineCache, ShapeId};
 use super::table::hash_string;
 use super::value::Value;
+use super::vm::VM;
 
-pub trait Object: Clone + fmt::Debug + fmt::Display + hash::Hash + PartialEq + Eq {}
+/// An object that can be allocated on the [`Heap`].
+pub trait Object: fmt::Debug + fmt::Display + Sized {
+    fn into_obj(gc: Gc<Self>) -> Obj;
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+    /// Marks every object this one references.
+    fn trace(&self, heap: &mut Heap);
+
+    /// Bytes owned by this object on top of its own size.
+    fn extra_size(&self) -> usize {
+        0
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum Obj {
-    String(Box<StringObj>),
-    Closure(Box<Closure>),
-    Function(Box<Function>),
-    NativeFunction(Box<NativeFunction>),
+    String(Gc<StringObj>),
+    Closure(Gc<Closure>),
+    Function(Gc<Function>),
+    NativeFunction(Gc<NativeFunction>),
+    Upvalue(Gc<Upvalue>),
+    Class(Gc<Class>),
+    Instance(Gc<Instance>),
+    BoundMethod(Gc<BoundMethod>),
+}
+
+macro_rules! for_each_obj {
+    ($obj:expr, $gc:ident => $body:expr) => {
+        match $obj {
+            Obj::String($gc) => $body,
+            Obj::Closure($gc) => $body,
+            Obj::Function($gc) => $body,
+            Obj::NativeFunction($gc) => $body,
+            Obj::Upvalue($gc) => $body,
+            Obj::Class($gc) => $body,
+            Obj::Instance($gc) => $body,
+            Obj::BoundMethod($gc) => $body,
+        }
+    };
+}
+
+impl Obj {
+    pub(super) fn mark(self) -> bool {
+        for_each_obj!(self, gc => memory::mark(gc))
+    }
+
+    pub(super) fn is_marked(self) -> bool {
+        for_each_obj!(self, gc => gc.is_marked())
+    }
+
+    pub(super) fn unmark(self) -> bool {
+        for_each_obj!(self, gc => memory::unmark(gc))
+    }
+
+    pub(super) fn trace(self, heap: &mut Heap) {
+        for_each_obj!(self, gc => gc.trace(heap))
+    }
+
+    /// # Safety
+    ///
+    /// No handle to the object may be used afterwards.
+    pub(super) unsafe fn free(self) -> usize {
+        for_each_obj!(self, gc => memory::free(gc))
+    }
+
+    /// Packs the handle into 48 bits, keeping the kind of object in the low bits of the address.
+    #[cfg(feature = "nan-boxing")]
+    pub(super) fn to_bits(self) -> u64 {
+        let (addr, tag) = match self {
+            Obj::String(gc) => (gc.addr(), 0),
+            Obj::Closure(gc) => (gc.addr(), 1),
+            Obj::Function(gc) => (gc.addr(), 2),
+            Obj::NativeFunction(gc) => (gc.addr(), 3),
+            Obj::Upvalue(gc) => (gc.addr(), 4),
+            Obj::Class(gc) => (gc.addr(), 5),
+            Obj::Instance(gc) => (gc.addr(), 6),
+            Obj::BoundMethod(gc) => (gc.addr(), 7),
+        };
+        debug_assert!(addr & 7 == 0 && addr >> 48 == 0, "Object address {:#x} can't be packed.", addr);
+        (addr | tag) as u64
+    }
+
+    /// # Safety
+    ///
+    /// `bits` must come from [`Obj::to_bits`].
+    #[cfg(feature = "nan-boxing")]
+    pub(super) unsafe fn from_bits(bits: u64) -> Obj {
+        let addr = (bits & !7) as usize;
+        match bits & 7 {
+            0 => Obj::String(Gc::from_addr(addr)),
+            1 => Obj::Closure(Gc::from_addr(addr)),
+            2 => Obj::Function(Gc::from_addr(addr)),
+            3 => Obj::NativeFunction(Gc::from_addr(addr)),
+            4 => Obj::Upvalue(Gc::from_addr(addr)),
+            5 => Obj::Class(Gc::from_addr(addr)),
+            6 => Obj::Instance(Gc::from_addr(addr)),
+            _ => Obj::BoundMethod(Gc::from_addr(addr)),
+        }
+    }
+}
+
+impl PartialEq for Obj {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            // Strings are interned, so equal strings are the same object.
+            (Obj::String(a), Obj::String(b)) => a == b,
+            (Obj::Closure(a), Obj::Closure(b)) => a == b,
+            (Obj::Function(a), Obj::Function(b)) => a == b,
+            (Obj::NativeFunction(a), Obj::NativeFunction(b)) => a == b,
+            (Obj::Upvalue(a), Obj::Upvalue(b)) => a == b,
+            (Obj::Class(a), Obj::Class(b)) => a == b,
+            (Obj::Instance(a), Obj::Instance(b)) => a == b,
+            (Obj::BoundMethod(a), Obj::BoundMethod(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Obj {}
+
+impl hash::Hash for Obj {
+    fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
+        for_each_obj!(self, gc => gc.hash(hasher))
+    }
 }
 
 impl fmt::Display for Obj {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Obj::String(string) => write!(f, "{}", string),
-            Obj::Function(function) => write!(f, "{}", function),
-            Obj::Closure(closure) => write!(f, "{}", closure),
-            Obj::NativeFunction(native_function) => write!(f, "{}", native_function),
-        }
+        for_each_obj!(self, gc => write!(f, "{}", gc))
     }
 }
 
@@ -41,7 +157,17 @@ impl StringObj {
     }
 }
 
-impl Object for StringObj {}
+impl Object for StringObj {
+    fn into_obj(gc: Gc<Self>) -> Obj {
+        Obj::String(gc)
+    }
+
+    fn trace(&self, _heap: &mut Heap) {}
+
+    fn extra_size(&self) -> usize {
+        self.string.capacity()
+    }
+}
 
 impl fmt::Display for StringObj {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -49,20 +175,37 @@ impl fmt::Display for StringObj {
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct Closure {
-    pub function: Box<Function>,
+    pub function: Gc<Function>,
+    pub upvalues: Vec<Gc<Upvalue>>,
 }
 
 impl Closure {
-    pub fn new(function: Box<Function>) -> Self {
+    pub fn new(function: Gc<Function>) -> Self {
         Closure {
+            upvalues: Vec::with_capacity(function.upvalue_count),
             function,
         }
     }
 }
 
-impl Object for Closure {}
+impl Object for Closure {
+    fn into_obj(gc: Gc<Self>) -> Obj {
+        Obj::Closure(gc)
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(Obj::Function(self.function));
+        for upvalue in &self.upvalues {
+            heap.mark_object(Obj::Upvalue(*upvalue));
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.upvalues.capacity() * mem::size_of::<Gc<Upvalue>>()
+    }
+}
 
 impl fmt::Display for Closure {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -70,24 +213,54 @@ impl fmt::Display for Closure {
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Function {
     pub arity: u8,
+    pub upvalue_count: usize,
     pub chunk: Chunk,
-    pub name: Option<Box<StringObj>>,
+    pub name: Option<Gc<StringObj>>,
 }
 
 impl Function {
     pub fn new() -> Self {
         Function {
             arity: 0,
+            upvalue_count: 0,
             chunk: Chunk::new(),
             name: None,
         }
     }
 }
 
-impl Object for Function {}
+impl Default for Function {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Object for Function {
+    fn into_obj(gc: Gc<Self>) -> Obj {
+        Obj::Function(gc)
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(name) = self.name {
+            heap.mark_object(Obj::String(name));
+        }
+        for constant in &self.chunk.constants {
+            heap.mark_value(*constant);
+        }
+        for cache in &self.chunk.caches {
+            if let InlineCache::Method { method, .. } = cache.get() {
+                heap.mark_object(Obj::Closure(method));
+            }
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.chunk.code.capacity() + self.chunk.constants.capacity() * mem::size_of::<Value>()
+    }
+}
 
 impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -99,25 +272,200 @@ impl fmt::Display for Function {
     }
 }
 
-pub type NativeFn = fn(arg_count: u8, args: &[Value]) -> Value;
+/// A function implemented in Rust. It gets exactly as many arguments as its arity, and an error
+/// becomes a runtime error with the given message.
+///
+/// Natives may capture host state, but not Lox values: the collector can't see inside them.
+pub type NativeFn = Box<dyn FnMut(&mut VM, &[Value]) -> Result<Value, String>>;
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct NativeFunction {
-    pub function: NativeFn,
+    pub name: String,
+    pub arity: u8,
+    pub function: RefCell<NativeFn>,
 }
 
 impl NativeFunction {
-    pub fn new(function: NativeFn) -> Self {
+    pub fn new(name: String, arity: u8, function: NativeFn) -> Self {
         NativeFunction {
-            function,
+            name,
+            arity,
+            function: RefCell::new(function),
         }
     }
 }
 
-impl Object for NativeFunction {}
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Object for NativeFunction {
+    fn into_obj(gc: Gc<Self>) -> Obj {
+        Obj::NativeFunction(gc)
+    }
+
+    fn trace(&self, _heap: &mut Heap) {}
+
+    fn extra_size(&self) -> usize {
+        self.name.capacity()
+    }
+}
 
 impl fmt::Display for NativeFunction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "<native fn>")
     }
 }
+
+/// A variable captured by a closure.
+///
+/// While the variable is still on the stack the upvalue is open and refers to its stack slot.
+/// Once the variable goes out of scope, the value is moved into `closed`.
+#[derive(Debug)]
+pub struct Upvalue {
+    pub slot: usize,
+    pub closed: Cell<Option<Value>>,
+}
+
+impl Upvalue {
+    pub fn new(slot: usize) -> Self {
+        Upvalue {
+            slot,
+            closed: Cell::new(None),
+        }
+    }
+}
+
+impl Object for Upvalue {
+    fn into_obj(gc: Gc<Self>) -> Obj {
+        Obj::Upvalue(gc)
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(value) = self.closed.get() {
+            heap.mark_value(value);
+        }
+    }
+}
+
+impl fmt::Display for Upvalue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "upvalue")
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: Gc<StringObj>,
+    pub methods: RefCell<HashMap<Gc<StringObj>, Gc<Closure>>>,
+    /// The shape of a new instance, with no fields.
+    pub shape: ShapeId,
+}
+
+impl Class {
+    pub fn new(name: Gc<StringObj>, shape: ShapeId) -> Self {
+        Class {
+            name,
+            methods: Default::default(),
+            shape,
+        }
+    }
+}
+
+impl Object for Class {
+    fn into_obj(gc: Gc<Self>) -> Obj {
+        Obj::Class(gc)
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(Obj::String(self.name));
+        for (name, method) in self.methods.borrow().iter() {
+            heap.mark_object(Obj::String(*name));
+            heap.mark_object(Obj::Closure(*method));
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Gc<Class>,
+    /// Which field is in which slot of `fields`.
+    pub shape: Cell<ShapeId>,
+    pub fields: RefCell<Vec<Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Gc<Class>) -> Self {
+        Instance {
+            class,
+            shape: Cell::new(class.shape),
+            fields: Default::default(),
+        }
+    }
+}
+
+impl Object for Instance {
+    fn into_obj(gc: Gc<Self>) -> Obj {
+        Obj::Instance(gc)
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(Obj::Class(self.class));
+        // Field names belong to the shape, which the VM keeps alive.
+        for value in self.fields.borrow().iter() {
+            heap.mark_value(*value);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.fields.borrow().capacity() * mem::size_of::<Value>()
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Gc<Closure>) -> Self {
+        BoundMethod {
+            receiver,
+            method,
+        }
+    }
+}
+
+impl Object for BoundMethod {
+    fn into_obj(gc: Gc<Self>) -> Obj {
+        Obj::BoundMethod(gc)
+    }
+
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_value(self.receiver);
+        heap.mark_object(Obj::Closure(self.method));
+    }
+}
+
+impl fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
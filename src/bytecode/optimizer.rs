@@ -0,0 +1,281 @@
+//! A peephole optimizer that rewrites a finished chunk, enabled with [`VMOptions::optimize`].
+//!
+//! The chunk is decoded into instructions with jump targets resolved to instruction indices, so
+//! instructions can be removed or rewritten freely. Encoding it again recomputes every jump offset
+//! and rebuilds the position table. The `.loxc` loader uses the same round trip to widen global
+//! instructions, see [`relink_globals`].
+//!
+//! [`VMOptions::optimize`]: super::vm::VMOptions::optimize
+
+use super::chunk::{Chunk, OpCode, Position};
+use super::value::ValueArray;
+
+struct Instruction {
+    op: OpCode,
+    /// Operand bytes, except for jumps, whose offset is kept in `target` instead.
+    operands: Vec<u8>,
+    /// Index of the instruction a jump goes to.
+    target: Option<usize>,
+    position: Position,
+    removed: bool,
+}
+
+impl Instruction {
+    fn is_jump(&self) -> bool {
+        matches!(
+            self.op,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue | OpCode::Loop | OpCode::LessJumpIfFalse
+                | OpCode::GreaterJumpIfFalse
+        )
+    }
+
+    fn is_unconditional_jump(&self) -> bool {
+        matches!(self.op, OpCode::Jump | OpCode::Loop)
+    }
+}
+
+pub fn optimize(chunk: &mut Chunk) {
+    let mut instructions = decode(chunk);
+    while rewrite(&mut instructions, &chunk.constants) {}
+    if let Some(optimized) = encode(chunk, &instructions) {
+        *chunk = optimized;
+    }
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let positions = chunk.byte_positions();
+    let mut instructions = Vec::new();
+    // Maps a byte offset to the instruction starting there, with one extra entry for the end.
+    let mut indices = vec![None; chunk.code.len() + 1];
+    let mut jumps = Vec::new();
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let len = chunk.instruction_len(offset);
+        let op = OpCode::try_from(chunk.code[offset]).unwrap();
+        indices[offset] = Some(instructions.len());
+        let mut instruction = Instruction {
+            op,
+            operands: chunk.code[offset + 1..offset + len].to_vec(),
+            target: None,
+            position: positions[offset],
+            removed: false,
+        };
+        if instruction.is_jump() {
+            let jump = (chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize;
+            let target = if matches!(op, OpCode::Loop) { offset + 3 - jump } else { offset + 3 + jump };
+            jumps.push((instructions.len(), target));
+            instruction.operands.clear();
+        }
+        instructions.push(instruction);
+        offset += len;
+    }
+    indices[offset] = Some(instructions.len());
+
+    for (index, target) in jumps {
+        instructions[index].target = indices[target];
+    }
+    instructions
+}
+
+/// The first instruction at or after `index` that hasn't been removed.
+fn live(instructions: &[Instruction], mut index: usize) -> usize {
+    while index < instructions.len() && instructions[index].removed {
+        index += 1;
+    }
+    index
+}
+
+/// Applies one round of rewrites, returning whether anything changed.
+fn rewrite(instructions: &mut [Instruction], constants: &ValueArray) -> bool {
+    let mut changed = false;
+
+    let mut targeted = vec![false; instructions.len() + 1];
+    for instruction in instructions.iter().filter(|instruction| !instruction.removed) {
+        if let Some(target) = instruction.target {
+            targeted[live(instructions, target)] = true;
+        }
+    }
+
+    let mut index = live(instructions, 0);
+    while index < instructions.len() {
+        let next = live(instructions, index + 1);
+        let after_next = live(instructions, next + 1);
+        let op = instructions[index].op;
+        let next_op = instructions.get(next).map(|instruction| instruction.op);
+
+        if instructions[index].is_jump() {
+            // Jump straight to where a chain of unconditional jumps ends up. Conditional jumps can
+            // only go forward, so they stop at a backward target.
+            let mut target = live(instructions, instructions[index].target.unwrap());
+            let mut hops = 0;
+            while target < instructions.len() && instructions[target].is_unconditional_jump() {
+                if hops == instructions.len() {
+                    // A cycle of jumps, as in `for (;;) {}`.
+                    break;
+                }
+                let next_target = live(instructions, instructions[target].target.unwrap());
+                if !instructions[index].is_unconditional_jump() && next_target <= index {
+                    break;
+                }
+                target = next_target;
+                hops += 1;
+            }
+            if Some(target) != instructions[index].target {
+                instructions[index].target = Some(target);
+                changed = true;
+            }
+
+            // A jump to the next instruction does nothing.
+            if matches!(op, OpCode::Jump) && target == next {
+                instructions[index].removed = true;
+                changed = true;
+            }
+        } else if matches!(
+            op,
+            OpCode::Constant | OpCode::ConstantLong | OpCode::Nil | OpCode::True | OpCode::False
+                | OpCode::GetLocal | OpCode::GetUpvalue
+        ) && matches!(next_op, Some(OpCode::Pop))
+            && !targeted[next]
+        {
+            // Pushing a value only to pop it again, as in an expression statement like `1;`.
+            instructions[index].removed = true;
+            instructions[next].removed = true;
+            changed = true;
+        } else if matches!(op, OpCode::Not)
+            && matches!(next_op, Some(OpCode::JumpIfFalse))
+            && !targeted[index]
+            && !targeted[next]
+        {
+            // The negated condition can be dropped if both branches pop it without looking at it.
+            let target = live(instructions, instructions[next].target.unwrap());
+            let pops = |index: usize| {
+                instructions.get(index).is_some_and(|instruction| matches!(instruction.op, OpCode::Pop))
+            };
+            if pops(after_next) && pops(target) {
+                instructions[index].removed = true;
+                instructions[next].op = OpCode::JumpIfTrue;
+                changed = true;
+            }
+        } else if matches!(op, OpCode::Less | OpCode::Greater)
+            && matches!(next_op, Some(OpCode::JumpIfFalse))
+            && instructions.get(after_next).is_some_and(|instruction| matches!(instruction.op, OpCode::Pop))
+            && !targeted[next]
+            && !targeted[after_next]
+        {
+            // Compare and jump in one go, when both branches start by popping the comparison.
+            let target = live(instructions, instructions[next].target.unwrap());
+            if instructions.get(target).is_some_and(|instruction| matches!(instruction.op, OpCode::Pop)) {
+                instructions[index].op =
+                    if matches!(op, OpCode::Less) { OpCode::LessJumpIfFalse } else { OpCode::GreaterJumpIfFalse };
+                instructions[index].target = Some(live(instructions, target + 1));
+                instructions[next].removed = true;
+                instructions[after_next].removed = true;
+                changed = true;
+            }
+        } else if matches!(op, OpCode::GetLocal)
+            && matches!(next_op, Some(OpCode::Constant))
+            && instructions.get(after_next).is_some_and(|instruction| {
+                matches!(instruction.op, OpCode::Add | OpCode::Subtract)
+            })
+            && !targeted[next]
+            && !targeted[after_next]
+            && constants[instructions[next].operands[0] as usize].as_number().is_some()
+        {
+            // Arithmetic on a local and a number, as in `n - 1`.
+            let constant = instructions[next].operands[0];
+            instructions[index].op = if matches!(instructions[after_next].op, OpCode::Add) {
+                OpCode::AddLocalConstant
+            } else {
+                OpCode::SubtractLocalConstant
+            };
+            instructions[index].operands.push(constant);
+            // Errors come from the arithmetic, so report them at its position.
+            instructions[index].position = instructions[after_next].position;
+            instructions[next].removed = true;
+            instructions[after_next].removed = true;
+            changed = true;
+        }
+
+        index = live(instructions, index + 1);
+    }
+    changed
+}
+
+/// Rewrites the operand of every global instruction with `slot`, switching to the long form
+/// wherever the new slot doesn't fit in a byte. Returns `false`, leaving the chunk as it was, if
+/// that stretches a jump further than its operand can hold.
+pub(super) fn relink_globals(chunk: &mut Chunk, slot: impl Fn(usize) -> usize) -> bool {
+    let mut instructions = decode(chunk);
+    for instruction in &mut instructions {
+        let long = match instruction.op {
+            OpCode::GetGlobal | OpCode::GetGlobalLong => OpCode::GetGlobalLong,
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong => OpCode::DefineGlobalLong,
+            OpCode::SetGlobal | OpCode::SetGlobalLong => OpCode::SetGlobalLong,
+            _ => continue,
+        };
+        let old = instruction.operands.iter().fold(0, |slot, byte| slot << 8 | *byte as usize);
+        let new = slot(old);
+        if new <= u8::MAX as usize && !instruction.op.has_long_operand() {
+            instruction.operands = vec![new as u8];
+        } else {
+            instruction.op = long;
+            instruction.operands = vec![(new >> 16) as u8, (new >> 8) as u8, new as u8];
+        }
+    }
+
+    match encode(chunk, &instructions) {
+        Some(relinked) => {
+            *chunk = relinked;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Encodes the rewritten instructions, or returns `None` if threading stretched a jump further
+/// than its operand can hold, in which case the chunk is best left as it was.
+fn encode(chunk: &mut Chunk, instructions: &[Instruction]) -> Option<Chunk> {
+    // Offsets of every instruction, where a removed one takes the offset of the next live one.
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        if !instruction.removed {
+            offset += 1 + if instruction.is_jump() { 2 } else { instruction.operands.len() };
+        }
+    }
+    offsets.push(offset);
+
+    let mut optimized = Chunk::new();
+    for (index, instruction) in instructions.iter().enumerate().filter(|(_, instruction)| !instruction.removed) {
+        let position = instruction.position;
+        match instruction.target {
+            Some(target) => {
+                let from = offsets[index] + 3;
+                let to = offsets[target];
+                // Threading can turn a forward jump into a backward one, but only unconditionally.
+                let (op, jump) = if to < from {
+                    (OpCode::Loop, from - to)
+                } else if matches!(instruction.op, OpCode::Loop) {
+                    (OpCode::Jump, to - from)
+                } else {
+                    (instruction.op, to - from)
+                };
+                let jump = u16::try_from(jump).ok()?;
+                optimized.write(op.into(), position);
+                optimized.write((jump >> 8) as u8, position);
+                optimized.write(jump as u8, position);
+            }
+            None => {
+                optimized.write(instruction.op.into(), position);
+                for byte in &instruction.operands {
+                    optimized.write(*byte, position);
+                }
+            }
+        }
+    }
+    optimized.constants = std::mem::take(&mut chunk.constants);
+    optimized.caches = std::mem::take(&mut chunk.caches);
+    Some(optimized)
+}
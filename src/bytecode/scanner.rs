@@ -1,3 +1,5 @@
+use super::chunk::Position;
+use crate::diagnostic::Span;
 use crate::impl_convert_enum_u8;
 
 // TODO: Implement C-style comma operator
@@ -63,7 +65,26 @@ pub struct Token {
     pub r#type: TokenType,
     pub lexeme: Box<str>,
     pub line: u32,
+    /// The column just past the end of the token.
     pub col: u32,
+    /// Where the token is in the source. For an error token, the text that caused the error.
+    pub span: Span,
+}
+
+impl Token {
+    /// Where the token starts. A token spanning lines is placed at the start of its last line,
+    /// since that is the line it reports.
+    pub fn position(&self) -> Position {
+        let column = match self.r#type {
+            // The lexeme is the error message, not source text.
+            TokenType::Error => self.col,
+            _ => {
+                let last_line = self.lexeme.rsplit('\n').next().unwrap_or_default();
+                self.col.saturating_sub(last_line.chars().count() as u32).max(1)
+            }
+        };
+        Position { line: self.line, column }
+    }
 }
 
 #[derive(Clone)]
@@ -152,7 +173,10 @@ impl Scanner<'_> {
 
     pub fn advance(&mut self) -> char {
         let c = self.peek();
-        self.current += 1;
+        if self.is_at_end() {
+            return c;
+        }
+        self.current += c.len_utf8();
 
         if c == '\n' {
             self.line += 1;
@@ -164,16 +188,12 @@ impl Scanner<'_> {
         c
     }
 
-    fn start(&self, index: usize) -> char {
-        self.source.chars().nth(self.start + index).unwrap()
-    }
-
     fn peek(&self) -> char {
-        self.source.chars().nth(self.current).unwrap_or('\0')
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        self.source.chars().nth(self.current + 1).unwrap_or('\0')
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn matches(&mut self, expected: char) -> bool {
@@ -191,6 +211,7 @@ impl Scanner<'_> {
             lexeme: (&self.source[self.start..self.current]).into(),
             line: self.line,
             col: self.col,
+            span: Span::new(self.start, self.current),
         }
     }
 
@@ -200,6 +221,7 @@ impl Scanner<'_> {
             lexeme: message.into(),
             line: self.line,
             col: self.col,
+            span: Span::new(self.start, self.current),
         }
     }
 
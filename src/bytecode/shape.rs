@@ -0,0 +1,202 @@
+//! Hidden classes for instances, so field lookups can be cached per instruction.
+//!
+//! Each class starts with an empty shape. Adding a field to an instance moves it to the shape
+//! with that field appended, which is created once and then shared by every instance of the class
+//! that gains the same fields in the same order. A shape therefore pins down both the class and
+//! the slot of every field, which is what [`InlineCache`] entries are keyed on.
+//!
+//! Shapes are collected along with the garbage: the VM marks the shapes that live classes,
+//! instances and inline caches still use, and [`Shapes::sweep`] frees the rest.
+
+use std::collections::HashMap;
+use std::mem;
+
+use super::memory::{Gc, Heap};
+use super::object::{Closure, Obj, StringObj};
+
+/// A shape in [`Shapes`]. The index of a freed shape is reused, but never with the same
+/// generation, so an id that outlives its shape doesn't match the next one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShapeId {
+    index: u32,
+    generation: u32,
+}
+
+struct Shape {
+    /// The slot of every field.
+    slots: HashMap<Gc<StringObj>, usize>,
+    /// The shapes reached by adding one more field.
+    transitions: HashMap<Gc<StringObj>, ShapeId>,
+    /// The shape this one was reached from, which is kept alive along with it.
+    parent: Option<ShapeId>,
+}
+
+impl Shape {
+    /// Bytes the shape takes up, counting its tables.
+    fn size(&self) -> usize {
+        mem::size_of::<Entry>()
+            + self.slots.capacity() * mem::size_of::<(Gc<StringObj>, usize)>()
+            + self.transitions.capacity() * mem::size_of::<(Gc<StringObj>, ShapeId)>()
+    }
+}
+
+struct Entry {
+    generation: u32,
+    /// `None` once the shape has been freed.
+    shape: Option<Shape>,
+    is_marked: bool,
+}
+
+/// Every shape the VM has created and not yet collected.
+///
+/// The field names of a shape in use are traced like roots, since a name that got freed and
+/// reallocated would otherwise match the wrong field.
+#[derive(Default)]
+pub struct Shapes {
+    entries: Vec<Entry>,
+    /// Indices of freed shapes, to be reused.
+    free: Vec<u32>,
+    bytes: usize,
+}
+
+impl Shapes {
+    /// A new empty shape, for a new class.
+    ///
+    /// It starts out marked, so that it survives a collection while its class is allocated.
+    pub fn root(&mut self) -> ShapeId {
+        let shape = self.push(Shape {
+            slots: HashMap::new(),
+            transitions: HashMap::new(),
+            parent: None,
+        });
+        self.entries[shape.index as usize].is_marked = true;
+        shape
+    }
+
+    /// Bytes the shapes that haven't been freed take up.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn slot(&self, shape: ShapeId, name: Gc<StringObj>) -> Option<usize> {
+        self.get(shape).slots.get(&name).copied()
+    }
+
+    /// The shape after adding the field `name` to `shape`, which must not have it yet.
+    pub fn transition(&mut self, shape: ShapeId, name: Gc<StringObj>) -> ShapeId {
+        // Transitions to freed shapes are dropped when they're freed, so this one is still live.
+        if let Some(next) = self.get(shape).transitions.get(&name) {
+            return *next;
+        }
+
+        let mut slots = self.get(shape).slots.clone();
+        slots.insert(name, slots.len());
+        let next = self.push(Shape {
+            slots,
+            transitions: HashMap::new(),
+            parent: Some(shape),
+        });
+        let parent = self.get_mut(shape);
+        let size = parent.size();
+        parent.transitions.insert(name, next);
+        let grown = parent.size() - size;
+        self.bytes += grown;
+        next
+    }
+
+    /// Marks `shape` as in use, along with the shapes it was reached from.
+    pub fn mark(&mut self, shape: ShapeId) {
+        let mut next = Some(shape);
+        while let Some(shape) = next {
+            let entry = &mut self.entries[shape.index as usize];
+            if entry.is_marked {
+                break;
+            }
+            entry.is_marked = true;
+            next = entry.shape.as_ref().and_then(|shape| shape.parent);
+        }
+    }
+
+    /// Marks the field names of every marked shape.
+    pub fn mark_names(&self, heap: &mut Heap) {
+        let marked = self.entries.iter().filter(|entry| entry.is_marked);
+        for shape in marked.filter_map(|entry| entry.shape.as_ref()) {
+            for name in shape.slots.keys() {
+                heap.mark_object(Obj::String(*name));
+            }
+        }
+    }
+
+    /// Frees every shape that wasn't marked and clears the marks on the rest.
+    pub fn sweep(&mut self) {
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            if entry.shape.is_some() && !mem::replace(&mut entry.is_marked, false) {
+                self.bytes -= entry.shape.take().map_or(0, |shape| shape.size());
+                entry.generation = entry.generation.wrapping_add(1);
+                self.free.push(index as u32);
+            }
+        }
+
+        // Forget the transitions to freed shapes, so their ids are never handed out again.
+        let generations: Vec<_> = self
+            .entries
+            .iter()
+            .map(|entry| entry.shape.is_some().then_some(entry.generation))
+            .collect();
+        for shape in self.entries.iter_mut().filter_map(|entry| entry.shape.as_mut()) {
+            shape.transitions.retain(|_, next| generations[next.index as usize] == Some(next.generation));
+        }
+    }
+
+    fn get(&self, shape: ShapeId) -> &Shape {
+        let entry = &self.entries[shape.index as usize];
+        debug_assert_eq!(entry.generation, shape.generation, "Shape used after it was freed.");
+        entry.shape.as_ref().expect("Shape used after it was freed.")
+    }
+
+    fn get_mut(&mut self, shape: ShapeId) -> &mut Shape {
+        let entry = &mut self.entries[shape.index as usize];
+        debug_assert_eq!(entry.generation, shape.generation, "Shape used after it was freed.");
+        entry.shape.as_mut().expect("Shape used after it was freed.")
+    }
+
+    fn push(&mut self, shape: Shape) -> ShapeId {
+        self.bytes += shape.size();
+        if let Some(index) = self.free.pop() {
+            let entry = &mut self.entries[index as usize];
+            entry.shape = Some(shape);
+            return ShapeId { index, generation: entry.generation };
+        }
+
+        let index = u32::try_from(self.entries.len()).expect("Too many shapes.");
+        self.entries.push(Entry { generation: 0, shape: Some(shape), is_marked: false });
+        ShapeId { index, generation: 0 }
+    }
+}
+
+/// What a property instruction found the last time it ran.
+///
+/// An entry only applies to instances of the shape it was made for. Once that shape has been
+/// collected, a new shape can take its index, but not its generation, so the entry just never
+/// hits again.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InlineCache {
+    #[default]
+    Empty,
+    /// The property is the field in `slot`.
+    Field { shape: ShapeId, slot: usize },
+    /// The property is a method of the class.
+    Method { shape: ShapeId, method: Gc<Closure> },
+    /// Setting the property adds it as a new field, moving the instance to the shape `to`. The VM
+    /// keeps `to` alive for as long as the entry is around, since a hit doesn't look it up.
+    Transition { shape: ShapeId, to: ShapeId },
+}
+
+/// How often property instructions found what they needed in their cache.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub property_hits: u64,
+    pub property_misses: u64,
+    pub invoke_hits: u64,
+    pub invoke_misses: u64,
+}
@@ -1,4 +1,5 @@
-use super::object::StringObj;
+use super::memory::{Gc, Heap};
+use super::object::{Obj, StringObj};
 use super::value::Value;
 
 #[inline]
@@ -10,93 +11,172 @@ fn grow_capacity(capacity: usize) -> usize {
     }
 }
 
+/// A slot in the table. An empty slot has no key and a `nil` value, while a tombstone left behind
+/// by [`Table::delete`] has no key and a `true` value.
 #[derive(Default, Clone, Debug)]
 pub struct Entry {
-    key: Option<StringObj>,
+    key: Option<Gc<StringObj>>,
     value: Value,
 }
 
+/// A hash table keyed by interned strings, using open addressing with linear probing.
+///
+/// Since every key is interned, keys are compared by identity and never by content.
+#[derive(Debug)]
 pub struct Table {
+    /// Number of occupied entries, tombstones included.
+    count: usize,
     entries: Vec<Entry>,
 }
 
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Table {
     const TABLE_MAX_LOAD: f64 = 0.75;
 
     pub fn new() -> Table {
         Table {
-            entries: Vec::with_capacity(8),
+            count: 0,
+            entries: Vec::new(),
         }
     }
 
-    pub fn set(&mut self, key: &StringObj, value: &Value) -> bool {
-        if (self.entries.len() + 1) as f64 > self.entries.capacity() as f64 * Self::TABLE_MAX_LOAD {
-            self.entries.resize(grow_capacity(self.entries.capacity()), Default::default());
+    pub fn get(&self, key: Gc<StringObj>) -> Option<Value> {
+        if self.count == 0 {
+            return None;
         }
 
-        let entry = self.find_entry(&key);
-        let is_new_key = entry.is_none();
+        let entry = &self.entries[Self::find_entry(&self.entries, key)];
+        entry.key.map(|_| entry.value)
+    }
+
+    /// Sets `key` to `value`, returning `true` if the key wasn't already in the table.
+    pub fn set(&mut self, key: Gc<StringObj>, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * Self::TABLE_MAX_LOAD {
+            self.adjust_capacity(grow_capacity(self.entries.len()));
+        }
 
-        if is_new_key {
-            self.entries.resize(self.entries.len() + 1, Default::default());
-            let entry = Entry {
-                key: Some(key.clone()),
-                value: value.clone(),
-            };
-            self.entries.push(entry);
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        // Reusing a tombstone doesn't change the count, it was already counted.
+        if is_new_key && entry.value.is_nil() {
+            self.count += 1;
         }
+
+        entry.key = Some(key);
+        entry.value = value;
         is_new_key
     }
 
-    pub fn delete(&mut self, key: &StringObj) -> bool {
-        if self.entries.len() == 0 {
+    pub fn delete(&mut self, key: Gc<StringObj>) -> bool {
+        if self.count == 0 {
             return false;
         }
 
         // Find the entry.
-        if let Some(ref mut entry) = self.find_entry(key) {
-            // Place a tombstone in the entry.
-            entry.key = None;
-            entry.value = Value::Bool(true);
-            return true;
-        } else {
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        if entry.key.is_none() {
             return false;
         }
+
+        // Place a tombstone in the entry.
+        entry.key = None;
+        entry.value = Value::bool(true);
+        true
     }
 
-    pub fn add_all(&mut self, to: &mut Table) {
+    pub fn add_all(&self, to: &mut Table) {
         for entry in &self.entries {
-            if let Some(key) = &entry.key {
-                to.set(&key, &entry.value);
+            if let Some(key) = entry.key {
+                to.set(key, entry.value);
             }
         }
     }
 
-    fn find_entry(&self, key: &StringObj) -> Option<Entry> {
-        let mut index = key.hash as usize % self.entries.capacity();
+    /// Looks up a string by content, for interning strings that haven't been allocated yet.
+    pub fn find_string(&self, string: &str, hash: u32) -> Option<Gc<StringObj>> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let capacity = self.entries.len();
+        let mut index = hash as usize % capacity;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                // Stop if we find an empty non-tombstone entry.
+                None if entry.value.is_nil() => return None,
+                Some(key) if key.hash == hash && key.string == string => return Some(key),
+                _ => {}
+            }
+
+            index = (index + 1) % capacity;
+        }
+    }
+
+    /// Marks every key and value in the table.
+    pub fn mark(&self, heap: &mut Heap) {
+        for entry in &self.entries {
+            if let Some(key) = entry.key {
+                heap.mark_object(Obj::String(key));
+                heap.mark_value(entry.value);
+            }
+        }
+    }
+
+    /// Deletes every entry whose key wasn't marked, so a table can hold its keys weakly.
+    pub fn remove_white(&mut self) {
+        for entry in &mut self.entries {
+            if entry.key.is_some_and(|key| !key.is_marked()) {
+                entry.key = None;
+                entry.value = Value::bool(true);
+            }
+        }
+    }
+
+    fn find_entry(entries: &[Entry], key: Gc<StringObj>) -> usize {
+        let capacity = entries.len();
+        let mut index = key.hash as usize % capacity;
         let mut tombstone = None;
         loop {
-            let entry = self.entries.get(index).unwrap();
-            if entry.key == None {
-                if entry.value == Value::Nil {
+            let entry = &entries[index];
+            match entry.key {
+                None if entry.value.is_nil() => {
                     // Empty entry.
-                    return Some(tombstone.unwrap_or(entry.clone()))
-                } else {
+                    return tombstone.unwrap_or(index);
+                }
+                None => {
                     // We found a tombstone.
                     if tombstone.is_none() {
-                        tombstone = Some(entry.clone());
+                        tombstone = Some(index);
                     }
                 }
-            } else if entry.key == Some(key.clone()) {
-                return Some(entry.clone());
+                Some(entry_key) if entry_key == key => return index,
+                Some(_) => {}
             }
 
-            index = (index + 1) % self.entries.capacity();
+            index = (index + 1) % capacity;
         }
     }
 
-    pub fn get(&self, key: &StringObj) -> Option<Value> {
-        self.find_entry(key).map(|entry| entry.value)
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let old_entries = std::mem::replace(&mut self.entries, vec![Entry::default(); capacity]);
+
+        // Tombstones aren't copied over, so the count has to be rebuilt.
+        self.count = 0;
+        for entry in old_entries {
+            if let Some(key) = entry.key {
+                let index = Self::find_entry(&self.entries, key);
+                self.entries[index] = entry;
+                self.count += 1;
+            }
+        }
     }
 }
 
@@ -1,61 +1,209 @@
 use std::fmt;
-use std::hash;
 
 use super::object::Obj;
-use crate::{impl_wrapper_type, impl_binary_ops_for_wrapper_type};
+
+pub use repr::Value;
 
 pub type ValueArray = Vec<Value>;
 
-#[derive(Clone, Default, Debug, Hash, PartialEq, Eq)]
-pub enum Value {
-    #[default]
-    Nil,
-    Bool(bool),
-    Number(HashableF64),
-    Obj(Obj),
-}
+/// A value as a plain enum, 16 bytes wide.
+#[cfg(not(feature = "nan-boxing"))]
+mod repr {
+    use super::Obj;
 
-impl Value {
-    pub fn is_falsey(&self) -> bool {
-        match self {
-            Value::Nil => true,
-            Value::Bool(value) => !value,
-            _ => false,
+    /// A Lox value: nil, a boolean, a number or a handle to a heap object.
+    ///
+    /// The representation depends on the `nan-boxing` feature, so values are only ever built and
+    /// taken apart through the methods below.
+    #[derive(Clone, Copy, Default, PartialEq)]
+    pub struct Value(Repr);
+
+    #[derive(Clone, Copy, Default, PartialEq)]
+    enum Repr {
+        #[default]
+        Nil,
+        Bool(bool),
+        Number(f64),
+        Obj(Obj),
+    }
+
+    impl Value {
+        pub const NIL: Value = Value(Repr::Nil);
+
+        #[inline]
+        pub fn bool(value: bool) -> Value {
+            Value(Repr::Bool(value))
+        }
+
+        #[inline]
+        pub fn number(value: f64) -> Value {
+            Value(Repr::Number(value))
+        }
+
+        #[inline]
+        pub fn obj(value: Obj) -> Value {
+            Value(Repr::Obj(value))
+        }
+
+        #[inline]
+        pub fn is_nil(self) -> bool {
+            matches!(self.0, Repr::Nil)
+        }
+
+        #[inline]
+        pub fn as_bool(self) -> Option<bool> {
+            match self.0 {
+                Repr::Bool(value) => Some(value),
+                _ => None,
+            }
+        }
+
+        #[inline]
+        pub fn as_number(self) -> Option<f64> {
+            match self.0 {
+                Repr::Number(value) => Some(value),
+                _ => None,
+            }
+        }
+
+        #[inline]
+        pub fn as_obj(self) -> Option<Obj> {
+            match self.0 {
+                Repr::Obj(value) => Some(value),
+                _ => None,
+            }
         }
     }
 }
 
-impl fmt::Display for Value {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Value::Bool(value) => write!(f, "{}", value),
-            Value::Nil => write!(f, "nil"),
-            Value::Number(value) => write!(f, "{}", value),
-            Value::Obj(value) => write!(f, "{}", value),
+/// A value packed into the bits of an `f64`, 8 bytes wide.
+///
+/// Numbers are stored as they are. Everything else lives in the payload of a quiet NaN that no
+/// arithmetic produces: nil and the booleans are small tags, and objects set the sign bit and keep
+/// their 48-bit address, with the kind of object in its low bits.
+#[cfg(feature = "nan-boxing")]
+mod repr {
+    use super::Obj;
+
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+
+    const NIL: u64 = QNAN | 1;
+    const FALSE: u64 = QNAN | 2;
+    const TRUE: u64 = QNAN | 3;
+
+    /// A Lox value: nil, a boolean, a number or a handle to a heap object.
+    ///
+    /// The representation depends on the `nan-boxing` feature, so values are only ever built and
+    /// taken apart through the methods below.
+    #[derive(Clone, Copy)]
+    pub struct Value(u64);
+
+    impl Value {
+        pub const NIL: Value = Value(NIL);
+
+        #[inline]
+        pub fn bool(value: bool) -> Value {
+            Value(if value { TRUE } else { FALSE })
+        }
+
+        #[inline]
+        pub fn number(value: f64) -> Value {
+            // Any other NaN could have the bits of a boxed object.
+            if value.is_nan() {
+                Value(f64::NAN.to_bits())
+            } else {
+                Value(value.to_bits())
+            }
+        }
+
+        #[inline]
+        pub fn obj(value: Obj) -> Value {
+            Value(SIGN_BIT | QNAN | value.to_bits())
+        }
+
+        #[inline]
+        pub fn is_nil(self) -> bool {
+            self.0 == NIL
+        }
+
+        #[inline]
+        pub fn as_bool(self) -> Option<bool> {
+            match self.0 {
+                TRUE => Some(true),
+                FALSE => Some(false),
+                _ => None,
+            }
+        }
+
+        #[inline]
+        pub fn as_number(self) -> Option<f64> {
+            if self.0 & QNAN != QNAN {
+                Some(f64::from_bits(self.0))
+            } else {
+                None
+            }
+        }
+
+        #[inline]
+        pub fn as_obj(self) -> Option<Obj> {
+            if self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+                // SAFETY: Only `Value::obj` sets these bits, since `Value::number` never stores a
+                // NaN with a payload.
+                Some(unsafe { Obj::from_bits(self.0 & !(SIGN_BIT | QNAN)) })
+            } else {
+                None
+            }
         }
     }
-}
 
-#[derive(Clone, Copy, Default, Debug, PartialEq, PartialOrd)]
-pub struct HashableF64(pub f64);
+    impl Default for Value {
+        fn default() -> Self {
+            Value::NIL
+        }
+    }
 
-impl_wrapper_type!(HashableF64, f64);
+    impl PartialEq for Value {
+        fn eq(&self, other: &Self) -> bool {
+            match (self.as_number(), other.as_number()) {
+                (Some(a), Some(b)) => a == b,
+                // Strings are interned, so equal strings are the same object.
+                _ => self.0 == other.0,
+            }
+        }
+    }
+}
 
-impl_binary_ops_for_wrapper_type!(HashableF64, f64, std::ops::Add, add, +);
-impl_binary_ops_for_wrapper_type!(HashableF64, f64, std::ops::Div, div, /);
-impl_binary_ops_for_wrapper_type!(HashableF64, f64, std::ops::Mul, mul, *);
-impl_binary_ops_for_wrapper_type!(HashableF64, f64, std::ops::Sub, sub, -);
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
+    }
+}
 
-impl fmt::Display for HashableF64 {
+impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        if let Some(value) = self.as_bool() {
+            write!(f, "{}", value)
+        } else if let Some(value) = self.as_number() {
+            write!(f, "{}", value)
+        } else if let Some(value) = self.as_obj() {
+            write!(f, "{}", value)
+        } else {
+            write!(f, "nil")
+        }
     }
 }
 
-impl hash::Hash for HashableF64 {
-    fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
-        hasher.write(&self.0.to_be_bytes());
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(value) = self.as_bool() {
+            write!(f, "Bool({:?})", value)
+        } else if let Some(value) = self.as_number() {
+            write!(f, "Number({:?})", value)
+        } else if let Some(value) = self.as_obj() {
+            write!(f, "Obj({:?})", value)
+        } else {
+            write!(f, "Nil")
+        }
     }
 }
-
-impl Eq for HashableF64 {}
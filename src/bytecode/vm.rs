@@ -1,20 +1,30 @@
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use super::chunk::{Chunk, OpCode};
 use super::compiler::compile;
-// use super::table::Table;
-type Table = std::collections::HashMap<StringObj, Value>;
-use super::object::{Obj, StringObj, Closure, NativeFunction, NativeFn};
-use super::value::{HashableF64, Value};
+use super::error::{Diagnostic, RuntimeError, RuntimeResult, TraceFrame};
+use super::interrupt::InterruptHandle;
+use super::memory::{Gc, Heap};
+use super::table::{hash_string, Table};
+use super::object::{Obj, Object, StringObj, BoundMethod, Class, Closure, Function, Instance, NativeFunction, Upvalue};
+use super::native::{FromLox, NativeModule};
+use super::shape::{CacheStats, InlineCache, ShapeId, Shapes};
+use super::value::Value;
+use crate::diagnostic::{self, Source};
 
 struct CallFrame {
-    closure: Box<Closure>,
+    closure: Gc<Closure>,
     ip: usize,
     slot: usize,
 }
 
 impl CallFrame {
-    pub fn new(closure: Box<Closure>, slot: usize) -> Self {
+    pub fn new(closure: Gc<Closure>, slot: usize) -> Self {
         CallFrame {
             closure,
             slot,
@@ -22,8 +32,8 @@ impl CallFrame {
         }
     }
 
-    pub fn chunk(&mut self) -> &mut Chunk {
-        &mut self.closure.function.chunk
+    pub fn chunk(&self) -> &Chunk {
+        &self.closure.function.chunk
     }
 
     fn read_byte(&mut self) -> u8 {
@@ -42,195 +52,639 @@ impl CallFrame {
 
     fn read_constant(&mut self) -> Value {
         let byte = self.read_byte() as usize;
-        self.chunk().constants[byte].clone()
+        self.chunk().constants[byte]
+    }
+
+    fn read_constant_long(&mut self) -> Value {
+        self.ip += 3;
+        let index = self.chunk().read_long(self.ip - 3);
+        self.chunk().constants[index]
+    }
+
+    /// Reads the index operand of `op`, in whichever width `op` uses.
+    fn read_operand(&mut self, op: OpCode) -> usize {
+        if op.has_long_operand() {
+            self.ip += 3;
+            self.chunk().read_long(self.ip - 3)
+        } else {
+            self.read_byte() as usize
+        }
+    }
+
+    /// Reads the constant operand of `op`, in whichever width `op` uses.
+    fn read_constant_operand(&mut self, op: OpCode) -> Value {
+        if op.has_long_operand() {
+            self.read_constant_long()
+        } else {
+            self.read_constant()
+        }
     }
 }
 
+/// A global variable, addressed by the slot the compiler gave its name.
+struct Global {
+    name: Gc<StringObj>,
+    /// `None` until the variable is defined.
+    value: Option<Value>,
+}
+
 pub struct VM {
     frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    globals: Vec<Global>,
+    /// The slot of every global name the compiler or the host has seen.
+    global_slots: HashMap<Gc<StringObj>, usize>,
+    shapes: Shapes,
+    cache_stats: CacheStats,
+    /// Every live string, so equal strings can share one object. Keys are held weakly.
     strings: Table,
-    globals: Table,
+    open_upvalues: Vec<Gc<Upvalue>>,
+    init_string: Option<Gc<StringObj>>,
+    /// Values the host holds on to, see [`VM::retain`].
+    handles: Vec<Option<Value>>,
+    /// Objects made by [`IntoLox`](super::native::IntoLox) that haven't reached the stack yet.
+    temporaries: Vec<Value>,
+    heap: Heap,
+    options: VMOptions,
+    /// How many more instructions may run. Without a limit this is refilled whenever it runs out,
+    /// which keeps the check in the dispatch loop down to a single comparison.
+    fuel: u64,
+    fuel_limited: bool,
+    /// Whether the frames on the stack belong to a script that ran out of fuel.
+    suspended: bool,
+    limits: Limits,
+    interrupt: InterruptHandle,
+    /// Where `print` statements write.
+    output: Box<dyn Write>,
+    /// Where errors are written.
+    diagnostics: Box<dyn Write>,
+    /// Whether to color rendered errors, which only happens while they go to a terminal.
+    color: bool,
+    /// Where execution traces and disassembled code are written.
+    pub(super) trace: Box<dyn Write>,
+}
+
+/// Debugging switches, which can be changed at any time with [`VM::set_options`].
+#[derive(Clone, Debug, Default)]
+pub struct VMOptions {
+    /// Write the stack and the instruction about to run before every instruction.
+    pub trace_execution: bool,
+    /// Disassemble every function as soon as it's compiled.
+    pub print_code: bool,
+    /// Run the peephole optimizer over every function once it's compiled.
+    pub optimize: bool,
+}
+
+/// Caps on the memory a script may use, set with [`VM::set_limits`]. Going over one is a runtime
+/// error, which unwinds the script like any other.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Bytes of objects on the heap, including the fields of instances and the shapes that lay
+    /// them out, checked after collecting garbage. Only the objects and fields a script creates
+    /// are refused; the compiler, natives and the host can still allocate past it.
+    pub max_heap_bytes: Option<usize>,
+    /// Values on the stack. This is only checked when a function is called, not on every push,
+    /// so the stack can go over it by the locals and temporaries of the function that's running.
+    pub max_stack_values: Option<usize>,
+    /// Calls in progress at once, counting the frame of the script itself. The default of 256
+    /// lets a script nest 255 calls.
+    pub max_frames: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_heap_bytes: None,
+            max_stack_values: None,
+            max_frames: Some(256),
+        }
+    }
+}
+
+/// A value the host keeps alive with [`VM::retain`], until it gives the handle back with
+/// [`VM::release`].
+///
+/// The value itself never leaves the VM, the host converts it with [`VM::get`] or passes it on
+/// with [`IntoLox`](super::native::IntoLox).
+#[derive(Debug)]
+pub struct Handle {
+    index: usize,
 }
 
 pub enum InterpretResult {
     Ok,
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+    /// The script used up its fuel and is suspended where it stopped. [`VM::resume`] continues it.
+    OutOfFuel,
+    /// The script was stopped through an [`InterruptHandle`] and its frames were dropped.
+    Interrupted(RuntimeError),
 }
 
 macro_rules! binary_op {
-    ($vm:ident, $value_type:expr, $op:tt) => {{
-        match ($vm.pop(), $vm.pop()) {
-            (Value::Number(b), Value::Number(a)) => {
-                $vm.push($value_type(a $op b));
+    ($vm:ident, $value_type:expr, $op:tt) => {
+        binary_op!($vm, |a, b| $value_type(a $op b))
+    };
+    ($vm:ident, |$a:ident, $b:ident| $result:expr) => {{
+        match ($vm.pop().as_number(), $vm.pop().as_number()) {
+            (Some($b), Some($a)) => {
+                $vm.push($result);
             }
-            (_, _) => return InterpretResult::RuntimeError,
+            (_, _) => return Err(runtime_error!($vm, "Operands must be numbers.")),
         }
-    }}
+    }};
 }
 
 macro_rules! runtime_error {
-    ($vm:ident, $format:literal$(, )?$($args:expr),*) => {{
-        eprintln!($format, $($args, )*);
-
-        for frame in $vm.frames.iter().rev() {
-            let function = &frame.closure.function;
-            let instruction = frame.ip - 1;
-            eprint!("[line {}] in ", &function.chunk.get_line(instruction));
-            if let Some(name) = &function.name {
-                eprintln!("{}()", name);
-            } else {
-                eprintln!("script");
-            }
-        }
-
-        $vm.reset_stack();
-    }}
+    ($vm:ident, $($args:tt)*) => {
+        $vm.runtime_error(format!($($args)*))
+    }
 }
 
-fn clock_native(_arg_count: u8, _args: &[Value]) -> Value {
-    Value::Number(
-        (SystemTime::now()
+fn clock_native(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::number(
+        SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or(Duration::new(0, 0))
-            .as_millis() as f64).into()
-    )
+            .as_millis() as f64
+    ))
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
+        Self::with_options(VMOptions::default())
+    }
+
+    pub fn with_options(options: VMOptions) -> Self {
         let mut result = VM {
             frames: Default::default(),
             stack: Default::default(),
+            globals: Vec::new(),
+            global_slots: HashMap::new(),
+            shapes: Shapes::default(),
+            cache_stats: CacheStats::default(),
             strings: Table::new(),
-            globals: Table::new(),
+            open_upvalues: Default::default(),
+            init_string: None,
+            handles: Vec::new(),
+            temporaries: Vec::new(),
+            heap: Heap::new(),
+            options,
+            fuel: u64::MAX,
+            fuel_limited: false,
+            suspended: false,
+            limits: Limits::default(),
+            interrupt: InterruptHandle::default(),
+            output: Box::new(io::stdout()),
+            diagnostics: Box::new(io::stderr()),
+            color: diagnostic::stderr_supports_color(),
+            trace: Box::new(io::stderr()),
         };
 
-        result.define_native("clock", clock_native);
+        result.init_string = Some(result.intern("init".to_string()));
+
+        result.define_native("clock", 0, clock_native);
 
         result
     }
 
+    /// Sets where `print` statements write. Defaults to stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Sets where errors are written. Defaults to stderr.
+    pub fn set_diagnostics(&mut self, diagnostics: impl Write + 'static) {
+        self.diagnostics = Box::new(diagnostics);
+        self.color = false;
+    }
+
+    /// Sets where the output of [`VMOptions::trace_execution`] and [`VMOptions::print_code`] is
+    /// written. Defaults to stderr.
+    pub fn set_trace_output(&mut self, trace: impl Write + 'static) {
+        self.trace = Box::new(trace);
+    }
+
+    pub fn options(&self) -> &VMOptions {
+        &self.options
+    }
+
+    pub fn set_options(&mut self, options: VMOptions) {
+        self.options = options;
+    }
+
+    /// Limits how many more instructions may run, or lifts the limit with `None`.
+    ///
+    /// A script that uses up its fuel stops with [`InterpretResult::OutOfFuel`] and can be resumed
+    /// once it has been given more. Running out inside [`VM::call_global`], [`VM::call_handle`]
+    /// or a script started by a native is a runtime error instead, since the host's own call is in
+    /// the way of suspending it.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel.unwrap_or(u64::MAX);
+        self.fuel_limited = fuel.is_some();
+    }
+
+    /// The fuel left, or `None` if there is no limit.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel_limited.then_some(self.fuel)
+    }
+
+    /// A handle for stopping the running script from another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// How often property accesses and invokes hit their inline cache so far.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache_stats
+    }
+
+    /// Writes any errors in `result` to the diagnostics sink, in the format the interpreter has
+    /// always used.
+    pub fn report(&mut self, result: &InterpretResult) -> io::Result<()> {
+        match result {
+            InterpretResult::Ok => (),
+            InterpretResult::CompileError(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(self.diagnostics, "{}", diagnostic)?;
+                }
+            }
+            InterpretResult::RuntimeError(error) | InterpretResult::Interrupted(error) => {
+                writeln!(self.diagnostics, "{}", error)?
+            }
+            InterpretResult::OutOfFuel => writeln!(self.diagnostics, "Out of fuel.")?,
+        }
+        Ok(())
+    }
+
+    /// Like [`VM::report`], but shows each compile error with the line of `source` it points at.
+    pub fn report_with_source(&mut self, result: &InterpretResult, source: &Source) -> io::Result<()> {
+        match result {
+            InterpretResult::CompileError(diagnostics) => {
+                for diagnostic in diagnostics {
+                    diagnostic.render(&mut self.diagnostics, source, self.color)?;
+                }
+                Ok(())
+            }
+            _ => self.report(result),
+        }
+    }
+
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let function = compile(source);
-        if let Some(function) = function {
-            let closure = Closure::new(function.clone().into());
-            let frame = CallFrame::new(closure.clone().into(), 0);
-            self.frames.push(frame);
+        let function = match compile(source, self) {
+            Ok(function) => function,
+            Err(diagnostics) => return InterpretResult::CompileError(diagnostics),
+        };
+        self.interpret_function(function)
+    }
 
-            self.push(Value::Obj(Obj::Function(function.clone().into())));
-            self.pop();
-            self.push(Value::Obj(Obj::Closure(closure.clone().into())));
-            self.call(closure, 0);
-        } else {
-            return InterpretResult::CompileError;
+    /// Runs a script that was already compiled, for instance one loaded with [`loxc::read`].
+    ///
+    /// [`loxc::read`]: super::loxc::read
+    pub fn interpret_function(&mut self, function: Gc<Function>) -> InterpretResult {
+        self.push(Value::obj(Obj::Function(function)));
+        let closure = self.allocate(Closure::new(function));
+        self.pop();
+
+        // Only a script with the stack to itself can be suspended and picked up again later.
+        let resumable = self.frames.is_empty();
+        let result = self.call_from_host(Value::obj(Obj::Closure(closure)), &[], resumable);
+        self.script_result(result)
+    }
+
+    /// Continues the script that stopped with [`InterpretResult::OutOfFuel`], which should be
+    /// given more fuel first. Returns [`InterpretResult::Ok`] if no script is suspended.
+    pub fn resume(&mut self) -> InterpretResult {
+        if !self.suspended {
+            return InterpretResult::Ok;
+        }
+        self.suspended = false;
+        self.interrupt.take();
+
+        let result = self.run(0, true);
+        if result.is_err() {
+            self.unwind(0, 0);
         }
+        self.script_result(result)
+    }
 
-        self.run()
+    fn script_result(&mut self, result: RuntimeResult<Option<Value>>) -> InterpretResult {
+        match result {
+            Ok(Some(_)) => InterpretResult::Ok,
+            Ok(None) => {
+                self.suspended = true;
+                InterpretResult::OutOfFuel
+            }
+            Err(error) if error.interrupted => InterpretResult::Interrupted(error),
+            Err(error) => InterpretResult::RuntimeError(error),
+        }
     }
 
-    fn run(&mut self) -> InterpretResult {
+    /// Calls the global function, class or native called `name` and returns a handle to its
+    /// result.
+    ///
+    /// Object arguments only have to be alive when the call starts, since they're on the stack
+    /// before the VM allocates anything.
+    pub fn call_global(&mut self, name: &str, args: &[Value]) -> RuntimeResult<Handle> {
+        // Interning the name could collect the arguments, and a global always has an interned name.
+        let name_string = self.strings.find_string(name, hash_string(name));
+        let slot = name_string.and_then(|name| self.global_slots.get(&name));
+        let Some(callee) = slot.and_then(|slot| self.globals[*slot].value) else {
+            return Err(runtime_error!(self, "Undefined variable '{}'.", name));
+        };
+
+        let result = self.call_from_host(callee, args, false)?.expect("Only resumable calls stop early.");
+        Ok(self.retain(result))
+    }
+
+    /// Calls the value behind `handle`, typically a closure a script handed to the host, and
+    /// returns a handle to its result.
+    pub fn call_handle(&mut self, handle: &Handle, args: &[Value]) -> RuntimeResult<Handle> {
+        let callee = self.handle_value(handle);
+        let result = self.call_from_host(callee, args, false)?.expect("Only resumable calls stop early.");
+        Ok(self.retain(result))
+    }
+
+    /// Keeps `value` alive until the handle is released, so the host can hold on to it.
+    pub fn retain(&mut self, value: Value) -> Handle {
+        let index = match self.handles.iter().position(Option::is_none) {
+            Some(index) => {
+                self.handles[index] = Some(value);
+                index
+            }
+            None => {
+                self.handles.push(Some(value));
+                self.handles.len() - 1
+            }
+        };
+        Handle { index }
+    }
+
+    /// Lets the value behind `handle` be collected once nothing else refers to it.
+    pub fn release(&mut self, handle: Handle) {
+        self.handles[handle.index] = None;
+    }
+
+    /// Converts the value behind `handle` to a Rust value.
+    pub fn get<T: FromLox>(&self, handle: &Handle) -> Result<T, String> {
+        T::from_lox(self.handle_value(handle))
+    }
+
+    pub(super) fn handle_value(&self, handle: &Handle) -> Value {
+        self.handles[handle.index].unwrap()
+    }
+
+    /// Keeps `value` alive until it reaches the stack, as the result of the native that's running
+    /// or as an argument to the next call from the host.
+    pub(super) fn hold(&mut self, value: Value) -> Value {
+        self.temporaries.push(value);
+        value
+    }
+
+    /// Calls `callee` on top of whatever is already running and runs until it returns, or until it
+    /// runs out of fuel if it's `resumable`.
+    ///
+    /// On error everything the call pushed is unwound, so the VM stays usable, even when the host
+    /// called back in from inside a native.
+    fn call_from_host(&mut self, callee: Value, args: &[Value], resumable: bool) -> RuntimeResult<Option<Value>> {
+        let Ok(arg_count) = u8::try_from(args.len()) else {
+            return Err(runtime_error!(self, "Can't have more than 255 arguments."));
+        };
+
+        let base_frame = self.frames.len();
+        if base_frame == 0 {
+            // Interrupting is meant for the script that was running, not one started afterwards.
+            // A call from inside a native leaves the request to the script around it.
+            self.interrupt.take();
+        }
+        let base_slot = self.stack.len();
+        self.push(callee);
+        self.stack.extend_from_slice(args);
+        if base_frame == 0 {
+            // Whatever the host converted is on the stack now, or it never will be.
+            self.temporaries.clear();
+        }
+
+        let result = self.call_value(callee, arg_count).and_then(|()| {
+            if self.frames.len() == base_frame {
+                // Natives and classes without an initializer have already left their result.
+                Ok(Some(self.pop()))
+            } else {
+                self.run(base_frame, resumable)
+            }
+        });
+
+        if result.is_err() {
+            self.unwind(base_frame, base_slot);
+        }
+        result
+    }
+
+    /// Drops the frames and stack slots from `base_frame` and `base_slot` on.
+    fn unwind(&mut self, base_frame: usize, base_slot: usize) {
+        self.close_upvalues(base_slot);
+        self.frames.truncate(base_frame);
+        self.stack.truncate(base_slot);
+    }
+
+    /// Writes the stack and the next instruction to the trace sink.
+    fn trace_instruction(&mut self) -> io::Result<()> {
+        write!(self.trace, "          ")?;
+        for slot in &self.stack {
+            write!(self.trace, "[ {} ]", *slot)?;
+        }
+        writeln!(self.trace)?;
+        let frame = self.frames.last().unwrap();
+        frame.chunk().disassemble_instruction(frame.ip, &mut self.trace)?;
+        Ok(())
+    }
+
+    /// Runs until the frame at `base_frame` returns, and returns its result.
+    ///
+    /// Running out of fuel returns `None` with every frame left in place if `resumable`, and is a
+    /// runtime error otherwise.
+    fn run(&mut self, base_frame: usize, resumable: bool) -> RuntimeResult<Option<Value>> {
         loop {
-            if cfg!(debug_assertions) {
-                print!("          ");
-                for slot in &self.stack {
-                    print!("[ {} ]", *slot);
-                }
-                println!();
-                let ip = self.current_frame().ip;
-                self.current_frame().chunk().disassemble_instruction(ip);
+            if self.fuel == 0 {
+                if !self.fuel_limited {
+                    self.fuel = u64::MAX;
+                } else if resumable {
+                    return Ok(None);
+                } else {
+                    return Err(runtime_error!(self, "Out of fuel."));
+                }
+            }
+            self.fuel -= 1;
+
+            if self.options.trace_execution {
+                // The trace is best effort, a failing sink shouldn't stop the program.
+                let _ = self.trace_instruction();
             }
 
             let instruction = self.current_frame().read_byte().try_into();
             match instruction {
-                Ok(OpCode::Constant) => {
-                    let constant = self.current_frame().read_constant();
+                Ok(op @ (OpCode::Constant | OpCode::ConstantLong)) => {
+                    let constant = self.current_frame().read_constant_operand(op);
                     self.push(constant);
                 }
-                Ok(OpCode::Nil) => self.push(Value::Nil),
-                Ok(OpCode::True) => self.push(Value::Bool(true)),
-                Ok(OpCode::False) => self.push(Value::Bool(false)),
+                Ok(OpCode::Nil) => self.push(Value::NIL),
+                Ok(OpCode::True) => self.push(Value::bool(true)),
+                Ok(OpCode::False) => self.push(Value::bool(false)),
                 Ok(OpCode::Pop) => {
                     self.pop();
                 }
                 Ok(OpCode::GetLocal) => {
                     let slot = self.current_frame().read_byte() as usize;
                     let slot_index = self.current_frame().slot + slot;
-                    let slot_value = self.stack[slot_index].clone();
+                    let slot_value = self.stack[slot_index];
                     self.push(slot_value);
                 }
                 Ok(OpCode::SetLocal) => {
                     let slot = self.current_frame().read_byte() as usize;
+                    let slot_index = self.current_frame().slot + slot;
                     let value = self.peek(0);
-                    self.stack[slot] = value;
-                }
-                Ok(OpCode::GetGlobal) => {
-                    if let Value::Obj(Obj::String(name)) = self.current_frame().read_constant() {
-                        if let Some(value) = self.globals.get(&name) {
-                            self.push(value.clone());
-                        } else {
-                            runtime_error!(self, "Undefined variable '{}'.", &name);
-                            return InterpretResult::RuntimeError;
-                        }
+                    self.stack[slot_index] = value;
+                }
+                Ok(op @ (OpCode::GetGlobal | OpCode::GetGlobalLong)) => {
+                    let slot = self.current_frame().read_operand(op);
+                    let global = &self.globals[slot];
+                    if let Some(value) = global.value {
+                        self.push(value);
+                    } else {
+                        return Err(runtime_error!(self, "Undefined variable '{}'.", global.name));
+                    }
+                }
+                Ok(op @ (OpCode::DefineGlobal | OpCode::DefineGlobalLong)) => {
+                    let slot = self.current_frame().read_operand(op);
+                    self.globals[slot].value = Some(self.pop());
+                }
+                Ok(op @ (OpCode::SetGlobal | OpCode::SetGlobalLong)) => {
+                    let slot = self.current_frame().read_operand(op);
+                    let value = self.peek(0);
+                    let global = &mut self.globals[slot];
+                    if global.value.is_none() {
+                        let name = global.name;
+                        return Err(runtime_error!(self, "Undefined variable '{}'.", name));
+                    }
+                    global.value = Some(value);
+                }
+                Ok(OpCode::GetUpvalue) => {
+                    let slot = self.current_frame().read_byte() as usize;
+                    let upvalue = self.current_frame().closure.upvalues[slot];
+                    let value = upvalue.closed.get().unwrap_or_else(|| self.stack[upvalue.slot]);
+                    self.push(value);
+                }
+                Ok(OpCode::SetUpvalue) => {
+                    let slot = self.current_frame().read_byte() as usize;
+                    let upvalue = self.current_frame().closure.upvalues[slot];
+                    let value = self.peek(0);
+                    if upvalue.closed.get().is_some() {
+                        upvalue.closed.set(Some(value));
+                    } else {
+                        self.stack[upvalue.slot] = value;
+                    }
+                }
+                Ok(op @ (OpCode::GetProperty | OpCode::GetPropertyLong)) => {
+                    let Some(Obj::Instance(instance)) = self.peek(0).as_obj() else {
+                        return Err(runtime_error!(self, "Only instances have properties."));
+                    };
+
+                    if let Some(Obj::String(name)) = self.current_frame().read_constant_operand(op).as_obj() {
+                        let closure = self.current_frame().closure;
+                        let cache = &closure.function.chunk.caches[self.current_frame().read_short() as usize];
+                        self.get_property(instance, name, cache)?;
                     }
                 }
-                Ok(OpCode::DefineGlobal) => {
-                    if let Value::Obj(Obj::String(name)) = self.current_frame().read_constant() {
+                Ok(op @ (OpCode::SetProperty | OpCode::SetPropertyLong)) => {
+                    let Some(Obj::Instance(instance)) = self.peek(1).as_obj() else {
+                        return Err(runtime_error!(self, "Only instances have fields."));
+                    };
+
+                    if let Some(Obj::String(name)) = self.current_frame().read_constant_operand(op).as_obj() {
+                        let closure = self.current_frame().closure;
+                        let cache = &closure.function.chunk.caches[self.current_frame().read_short() as usize];
                         let value = self.peek(0);
-                        self.globals.insert(*name, value);
+                        self.set_property(instance, name, value, cache)?;
                         self.pop();
+                        self.pop(); // Instance.
+                        self.push(value);
                     }
                 }
-                Ok(OpCode::SetGlobal) => {
-                    if let Value::Obj(Obj::String(name)) = self.current_frame().read_constant() {
-                        let value = self.peek(0);
-                        if self.globals.insert(*name.clone(), value).is_none() {
-                            self.globals.remove(&name);
-                            runtime_error!(self, "Undefined variable '{}'.", name);
-                            return InterpretResult::RuntimeError;
+                Ok(op @ (OpCode::GetSuper | OpCode::GetSuperLong)) => {
+                    if let Some(Obj::String(name)) = self.current_frame().read_constant_operand(op).as_obj() {
+                        if let Some(Obj::Class(superclass)) = self.pop().as_obj() {
+                            let method = self.find_method(superclass, name)?;
+                            self.bind_method(method)?;
                         }
                     }
                 }
                 Ok(OpCode::Equal) => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Bool(a == b));
+                    self.push(Value::bool(a == b));
+                }
+                Ok(OpCode::NotEqual) => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::bool(a != b));
                 }
-                Ok(OpCode::Greater) => binary_op!(self, Value::Bool, >),
-                Ok(OpCode::Less) => binary_op!(self, Value::Bool, <),
+                Ok(OpCode::Greater) => binary_op!(self, Value::bool, >),
+                Ok(OpCode::Less) => binary_op!(self, Value::bool, <),
+                // These used to compile to `Less` or `Greater` and `Not`, which NaN satisfies.
+                Ok(OpCode::GreaterEqual) => binary_op!(self, |a, b| Value::bool(a.partial_cmp(&b) != Some(Ordering::Less))),
+                Ok(OpCode::LessEqual) => binary_op!(self, |a, b| Value::bool(a.partial_cmp(&b) != Some(Ordering::Greater))),
                 Ok(OpCode::Add) => {
-                    match (self.pop(), self.pop()) {
-                        (Value::Number(b), Value::Number(a)) => {
-                            self.push(Value::Number(a + b));
-                        }
-                        (Value::Obj(Obj::String(b)), Value::Obj(Obj::String(a))) => {
-                            let value = Value::Obj(Obj::String(self.allocate_string(a.string + &b.string).into()));
-                            self.push(value);
-                        }
-                        (_, _) => {
-                            runtime_error!(self, "Operands must be two numbers or two strings.");
-                            return InterpretResult::RuntimeError
-                        }
+                    let (b, a) = (self.peek(0), self.peek(1));
+                    if let (Some(b), Some(a)) = (b.as_number(), a.as_number()) {
+                        self.pop();
+                        self.pop();
+                        self.push(Value::number(a + b));
+                    } else if let (Some(Obj::String(b)), Some(Obj::String(a))) = (b.as_obj(), a.as_obj()) {
+                        // Both operands stay on the stack until the result is allocated so the
+                        // collector can see them.
+                        self.reserve(a.string.len() + b.string.len(), |_| ())?;
+                        let string = self.intern(a.string.clone() + &b.string);
+                        self.pop();
+                        self.pop();
+                        self.push(Value::obj(Obj::String(string)));
+                    } else {
+                        return Err(runtime_error!(self, "Operands must be two numbers or two strings."));
                     }
                 }
-                Ok(OpCode::Subtract) => binary_op!(self, Value::Number, -),
-                Ok(OpCode::Multiply) => binary_op!(self, Value::Number, *),
-                Ok(OpCode::Divide) => binary_op!(self, Value::Number, /),
+                Ok(OpCode::Subtract) => binary_op!(self, Value::number, -),
+                Ok(OpCode::Multiply) => binary_op!(self, Value::number, *),
+                Ok(OpCode::Divide) => binary_op!(self, Value::number, /),
                 Ok(OpCode::Not) => {
-                    let value = Value::Bool(self.pop().is_falsey());
+                    let value = Value::bool(self.pop().is_falsey());
                     self.push(value);
                 }
                 Ok(OpCode::Negate) => {
-                    if let Value::Number(ref mut value) = *self.stack.last_mut().unwrap() {
-                        *value = *value * HashableF64(-1.0);
+                    let top = self.stack.last_mut().unwrap();
+                    if let Some(value) = top.as_number() {
+                        *top = Value::number(-value);
                     } else {
-                        runtime_error!(self, "Operand must be a number.");
-                        return InterpretResult::RuntimeError;
+                        return Err(runtime_error!(self, "Operand must be a number."));
                     }
                 }
                 Ok(OpCode::Print) => {
-                    println!("{}", self.pop());
+                    let value = self.pop();
+                    if let Err(error) = writeln!(self.output, "{}", value) {
+                        return Err(runtime_error!(self, "Could not write output: {}.", error));
+                    }
                 }
                 Ok(OpCode::Jump) => {
                     let offset = self.current_frame().read_short() as usize;
@@ -242,32 +696,117 @@ impl VM {
                         self.current_frame().ip += offset;
                     }
                 }
+                Ok(OpCode::JumpIfTrue) => {
+                    let offset = self.current_frame().read_short() as usize;
+                    if !self.peek(0).is_falsey() {
+                        self.current_frame().ip += offset;
+                    }
+                }
                 Ok(OpCode::Loop) => {
+                    self.check_interrupt()?;
                     let offset = self.current_frame().read_short() as usize;
                     self.current_frame().ip -= offset;
                 }
                 Ok(OpCode::Call) => {
                     let arg_count = self.current_frame().read_byte();
                     let value = self.peek(arg_count.into());
-                    if !self.call_value(value, arg_count) {
-                        return InterpretResult::RuntimeError;
+                    self.call_value(value, arg_count)?;
+                }
+                Ok(op @ (OpCode::Invoke | OpCode::InvokeLong)) => {
+                    if let Some(Obj::String(method)) = self.current_frame().read_constant_operand(op).as_obj() {
+                        let arg_count = self.current_frame().read_byte();
+                        let closure = self.current_frame().closure;
+                        let cache = &closure.function.chunk.caches[self.current_frame().read_short() as usize];
+                        self.invoke(method, arg_count, cache)?;
                     }
                 }
-                Ok(OpCode::Closure) => {
-                    if let Value::Obj(Obj::Function(function)) = self.current_frame().read_constant() {
-                        let closure = Closure::new(function);
-                        self.push(Value::Obj(Obj::Closure(closure.into())));
+                Ok(op @ (OpCode::SuperInvoke | OpCode::SuperInvokeLong)) => {
+                    if let Some(Obj::String(method)) = self.current_frame().read_constant_operand(op).as_obj() {
+                        let arg_count = self.current_frame().read_byte();
+                        if let Some(Obj::Class(superclass)) = self.pop().as_obj() {
+                            self.invoke_from_class(superclass, method, arg_count)?;
+                        }
+                    }
+                }
+                Ok(op @ (OpCode::Closure | OpCode::ClosureLong)) => {
+                    if let Some(Obj::Function(function)) = self.current_frame().read_constant_operand(op).as_obj() {
+                        let mut closure = Closure::new(function);
+                        for _ in 0..function.upvalue_count {
+                            let is_local = self.current_frame().read_byte() == 1;
+                            let index = self.current_frame().read_byte() as usize;
+                            let upvalue = if is_local {
+                                let slot = self.current_frame().slot + index;
+                                self.capture_upvalue(slot)?
+                            } else {
+                                self.current_frame().closure.upvalues[index]
+                            };
+                            closure.upvalues.push(upvalue);
+                        }
+                        let closure = self.try_allocate(closure)?;
+                        self.push(Value::obj(Obj::Closure(closure)));
+                    }
+                }
+                Ok(OpCode::CloseUpvalue) => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Ok(op @ (OpCode::Class | OpCode::ClassLong)) => {
+                    if let Some(Obj::String(name)) = self.current_frame().read_constant_operand(op).as_obj() {
+                        let shape = self.shapes.root();
+                        let class = self.try_allocate(Class::new(name, shape))?;
+                        self.push(Value::obj(Obj::Class(class)));
+                    }
+                }
+                Ok(OpCode::Inherit) => {
+                    let Some(Obj::Class(superclass)) = self.peek(1).as_obj() else {
+                        return Err(runtime_error!(self, "Superclass must be a class."));
+                    };
+
+                    if let Some(Obj::Class(subclass)) = self.peek(0).as_obj() {
+                        let methods = superclass.methods.borrow().clone();
+                        subclass.methods.borrow_mut().extend(methods);
+                    }
+                    self.pop(); // Subclass.
+                }
+                Ok(op @ (OpCode::Method | OpCode::MethodLong)) => {
+                    if let Some(Obj::String(name)) = self.current_frame().read_constant_operand(op).as_obj() {
+                        self.define_method(name);
+                    }
+                }
+                Ok(op @ (OpCode::AddLocalConstant | OpCode::SubtractLocalConstant)) => {
+                    let slot = self.current_frame().read_byte() as usize;
+                    let constant = self.current_frame().read_constant();
+                    let slot_index = self.current_frame().slot + slot;
+                    let local = self.stack[slot_index];
+                    match (local.as_number(), constant.as_number(), op) {
+                        (Some(a), Some(b), OpCode::AddLocalConstant) => self.push(Value::number(a + b)),
+                        (Some(a), Some(b), _) => self.push(Value::number(a - b)),
+                        (_, _, OpCode::AddLocalConstant) => {
+                            return Err(runtime_error!(self, "Operands must be two numbers or two strings."));
+                        }
+                        (_, _, _) => return Err(runtime_error!(self, "Operands must be numbers.")),
+                    }
+                }
+                Ok(op @ (OpCode::LessJumpIfFalse | OpCode::GreaterJumpIfFalse)) => {
+                    let offset = self.current_frame().read_short() as usize;
+                    let (Some(b), Some(a)) = (self.pop().as_number(), self.pop().as_number()) else {
+                        return Err(runtime_error!(self, "Operands must be numbers."));
+                    };
+                    let holds = if matches!(op, OpCode::LessJumpIfFalse) { a < b } else { a > b };
+                    if !holds {
+                        self.current_frame().ip += offset;
                     }
                 }
                 Ok(OpCode::Return) => {
                     let result = self.pop();
+                    let slot = self.current_frame().slot;
+                    self.close_upvalues(slot);
                     let prev_frame = self.frames.pop().unwrap();
-                    if self.frames.len() <= 1 {
-                        self.pop();
-                        return InterpretResult::Ok;
+                    self.stack.truncate(prev_frame.slot);
+                    if self.frames.len() == base_frame {
+                        return Ok(Some(result));
                     }
 
-                    self.stack.truncate(prev_frame.slot);
                     self.push(result);
                 }
                 _ => (),
@@ -275,9 +814,45 @@ impl VM {
         }
     }
 
+    /// Builds a runtime error with a trace of the current call stack.
+    fn runtime_error(&self, message: String) -> RuntimeError {
+        let trace: Vec<_> = self.frames.iter().rev().map(|frame| {
+            let function = &frame.closure.function;
+            // A frame that ran out of fuel before its first instruction is still at offset 0.
+            let position = function.chunk.get_position(frame.ip.saturating_sub(1));
+            TraceFrame {
+                function: function.name.map(|name| name.string.clone()),
+                line: position.line,
+                column: position.column,
+            }
+        }).collect();
+
+        RuntimeError {
+            message,
+            line: trace.first().map_or(0, |frame| frame.line),
+            column: trace.first().map_or(0, |frame| frame.column),
+            trace,
+            interrupted: false,
+        }
+    }
+
+    /// Fails with an "Interrupted." error if an [`InterruptHandle`] asked the VM to stop.
+    fn check_interrupt(&self) -> RuntimeResult<()> {
+        if self.interrupt.take() {
+            let mut error = runtime_error!(self, "Interrupted.");
+            error.interrupted = true;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Drops everything on the stack, including a script that ran out of fuel.
     pub fn reset_stack(&mut self) {
+        // Closures the script left behind keep the values they captured.
+        self.close_upvalues(0);
         self.stack = Default::default();
         self.frames = Default::default();
+        self.suspended = false;
     }
 
     fn push(&mut self, value: Value) {
@@ -289,67 +864,466 @@ impl VM {
     }
 
     fn peek(&self, distance: usize) -> Value {
-        self.stack[self.stack.len() - distance - 1].clone()
+        self.stack[self.stack.len() - distance - 1]
     }
 
     fn current_frame(&mut self) -> &mut CallFrame {
         self.frames.last_mut().unwrap()
     }
 
-    fn call(&mut self, closure: Closure, arg_count: u8) -> bool {
+    fn call(&mut self, closure: Gc<Closure>, arg_count: u8) -> RuntimeResult<()> {
+        self.check_interrupt()?;
         if arg_count != closure.function.arity {
-            runtime_error!(self, "Expected {} argument(s) but got {}.", closure.function.arity, arg_count);
-            return false;
+            return Err(runtime_error!(self, "Expected {} arguments but got {}.", closure.function.arity, arg_count));
         }
 
-        if self.frames.len() > 256 {
-            runtime_error!(self, "Stack overflow");
-            return false;
+        if self.limits.max_frames.is_some_and(|max| self.frames.len() >= max) {
+            return Err(runtime_error!(self, "Stack overflow."));
+        }
+        if let Some(max) = self.limits.max_stack_values.filter(|max| self.stack.len() > *max) {
+            return Err(runtime_error!(self, "Stack overflow: more than {} values on the stack.", max));
         }
 
         let frame = CallFrame::new(
-            closure.into(),
+            closure,
             self.stack.len() - arg_count as usize - 1
         );
         self.frames.push(frame);
-        true
+        Ok(())
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
-        if let Value::Obj(callee) = callee {
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> RuntimeResult<()> {
+        if let Some(callee) = callee.as_obj() {
             match callee {
-                Obj::Closure(closure) => self.call(*closure, arg_count),
+                Obj::BoundMethod(bound_method) => {
+                    let slot = self.stack.len() - arg_count as usize - 1;
+                    self.stack[slot] = bound_method.receiver;
+                    self.call(bound_method.method, arg_count)
+                }
+                Obj::Class(class) => {
+                    let slot = self.stack.len() - arg_count as usize - 1;
+                    let instance = self.try_allocate(Instance::new(class))?;
+                    self.stack[slot] = Value::obj(Obj::Instance(instance));
+                    let initializer = self.init_string.and_then(|init| class.methods.borrow().get(&init).copied());
+                    if let Some(initializer) = initializer {
+                        self.call(initializer, arg_count)
+                    } else if arg_count != 0 {
+                        Err(runtime_error!(self, "Expected 0 arguments but got {}.", arg_count))
+                    } else {
+                        Ok(())
+                    }
+                }
+                Obj::Closure(closure) => self.call(closure, arg_count),
                 Obj::NativeFunction(native_function) => {
-                    let result = (native_function.function)(arg_count, &[self.peek(arg_count as usize)]);
-                    let new_stack_size = self.stack.len() - arg_count as usize + 1;
-                    self.stack.truncate(new_stack_size);
-                    self.push(result);
-                    true
+                    if arg_count != native_function.arity {
+                        return Err(runtime_error!(self, "Expected {} arguments but got {}.", native_function.arity, arg_count));
+                    }
+
+                    let Ok(mut function) = native_function.function.try_borrow_mut() else {
+                        return Err(runtime_error!(self, "Native function '{}' can't call itself.", native_function.name));
+                    };
+
+                    // The arguments stay on the stack while the native runs, so they stay rooted.
+                    let args_start = self.stack.len() - arg_count as usize;
+                    let args = self.stack[args_start..].to_vec();
+                    let temporaries = self.temporaries.len();
+                    let result = function(self, &args);
+                    self.temporaries.truncate(temporaries);
+                    match result {
+                        Ok(result) => {
+                            // Drop the arguments and the native function itself.
+                            self.stack.truncate(args_start - 1);
+                            self.push(result);
+                            Ok(())
+                        }
+                        Err(message) => Err(runtime_error!(self, "{}", message)),
+                    }
                 }
                 _ => {
-                    runtime_error!(self, "Can only call functions and classes.");
-                    false
+                    Err(runtime_error!(self, "Can only call functions and classes."))
+                }
+            }
+        } else {
+            Err(runtime_error!(self, "Can only call functions and classes."))
+        }
+    }
+
+    fn invoke_from_class(&mut self, class: Gc<Class>, name: Gc<StringObj>, arg_count: u8) -> RuntimeResult<()> {
+        let method = self.find_method(class, name)?;
+        self.call(method, arg_count)
+    }
+
+    fn invoke(&mut self, name: Gc<StringObj>, arg_count: u8, cache: &Cell<InlineCache>) -> RuntimeResult<()> {
+        let receiver = self.peek(arg_count as usize);
+        let Some(Obj::Instance(instance)) = receiver.as_obj() else {
+            return Err(runtime_error!(self, "Only instances have methods."));
+        };
+
+        let shape = instance.shape.get();
+        let property = match cache.get() {
+            hit @ (InlineCache::Field { shape: cached, .. } | InlineCache::Method { shape: cached, .. })
+                if cached == shape =>
+            {
+                self.cache_stats.invoke_hits += 1;
+                hit
+            }
+            _ => {
+                self.cache_stats.invoke_misses += 1;
+                let property = self.find_property(instance, name)?;
+                cache.set(property);
+                property
+            }
+        };
+
+        match property {
+            InlineCache::Field { slot, .. } => {
+                let value = instance.fields.borrow()[slot];
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = value;
+                self.call_value(value, arg_count)
+            }
+            InlineCache::Method { method, .. } => self.call(method, arg_count),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Replaces the instance on top of the stack with its property `name`.
+    fn get_property(&mut self, instance: Gc<Instance>, name: Gc<StringObj>, cache: &Cell<InlineCache>) -> RuntimeResult<()> {
+        let shape = instance.shape.get();
+        let property = match cache.get() {
+            hit @ (InlineCache::Field { shape: cached, .. } | InlineCache::Method { shape: cached, .. })
+                if cached == shape =>
+            {
+                self.cache_stats.property_hits += 1;
+                hit
+            }
+            _ => {
+                self.cache_stats.property_misses += 1;
+                let property = self.find_property(instance, name)?;
+                cache.set(property);
+                property
+            }
+        };
+
+        match property {
+            InlineCache::Field { slot, .. } => {
+                let value = instance.fields.borrow()[slot];
+                self.pop(); // Instance.
+                self.push(value);
+            }
+            InlineCache::Method { method, .. } => self.bind_method(method)?,
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Sets a property the script assigns, keeping the instance and its shapes under
+    /// [`Limits::max_heap_bytes`]. The instance and `value` have to be on the stack.
+    fn set_property(&mut self, instance: Gc<Instance>, name: Gc<StringObj>, value: Value, cache: &Cell<InlineCache>) -> RuntimeResult<()> {
+        let shape = instance.shape.get();
+        match cache.get() {
+            InlineCache::Field { shape: cached, slot } if cached == shape => {
+                self.cache_stats.property_hits += 1;
+                instance.fields.borrow_mut()[slot] = value;
+            }
+            InlineCache::Transition { shape: cached, to } if cached == shape => {
+                self.cache_stats.property_hits += 1;
+                self.reserve_field(instance)?;
+                self.add_field(instance, to, value);
+            }
+            _ => {
+                self.cache_stats.property_misses += 1;
+                // Room for the field is made before the transition creates its shape, which
+                // nothing would keep alive through a collection until the instance moves to it.
+                if self.shapes.slot(shape, name).is_none() {
+                    self.reserve_field(instance)?;
                 }
+                cache.set(self.set_field(instance, name, value));
+                // The new shape is only counted once it exists.
+                self.reserve(0, |_| ())?;
             }
+        }
+        Ok(())
+    }
+
+    /// Looks up property `name` without a cache, as a [`InlineCache::Field`] or
+    /// [`InlineCache::Method`] entry for the instance's current shape.
+    fn find_property(&mut self, instance: Gc<Instance>, name: Gc<StringObj>) -> RuntimeResult<InlineCache> {
+        let shape = instance.shape.get();
+        if let Some(slot) = self.shapes.slot(shape, name) {
+            Ok(InlineCache::Field { shape, slot })
+        } else {
+            let method = self.find_method(instance.class, name)?;
+            Ok(InlineCache::Method { shape, method })
+        }
+    }
+
+    /// Sets field `name` without a cache, adding it if the instance doesn't have it yet. Returns
+    /// the cache entry that would have done the same.
+    fn set_field(&mut self, instance: Gc<Instance>, name: Gc<StringObj>, value: Value) -> InlineCache {
+        let shape = instance.shape.get();
+        if let Some(slot) = self.shapes.slot(shape, name) {
+            instance.fields.borrow_mut()[slot] = value;
+            InlineCache::Field { shape, slot }
         } else {
-            runtime_error!(self, "Can only call functions and classes.");
-            false
+            let to = self.shapes.transition(shape, name);
+            self.add_field(instance, to, value);
+            InlineCache::Transition { shape, to }
+        }
+    }
+
+    /// Checks that the fields of `instance` can grow by one under [`Limits::max_heap_bytes`].
+    fn reserve_field(&mut self, instance: Gc<Instance>) -> RuntimeResult<()> {
+        let (len, capacity) = {
+            let fields = instance.fields.borrow();
+            (fields.len(), fields.capacity())
+        };
+        if len < capacity {
+            return Ok(());
         }
+        // A full vector at least doubles, to no fewer than four values.
+        self.reserve(capacity.max(4) * mem::size_of::<Value>(), |_| ())
+    }
+
+    /// Appends `value` to the fields of `instance`, moving it to shape `to`.
+    fn add_field(&mut self, instance: Gc<Instance>, to: ShapeId, value: Value) {
+        let grown = {
+            let mut fields = instance.fields.borrow_mut();
+            let capacity = fields.capacity();
+            fields.push(value);
+            fields.capacity() - capacity
+        };
+        instance.shape.set(to);
+        self.heap.grow(instance, grown * mem::size_of::<Value>());
     }
 
-    fn allocate_string(&mut self, string: String) -> StringObj {
-        let string = StringObj::new(string);
-        self.strings.insert(string.clone(), Value::Nil);
-        string
+    fn find_method(&mut self, class: Gc<Class>, name: Gc<StringObj>) -> RuntimeResult<Gc<Closure>> {
+        let method = class.methods.borrow().get(&name).copied();
+        method.ok_or_else(|| runtime_error!(self, "Undefined property '{}'.", name))
     }
 
-    fn define_native(&mut self, name: &str, function: NativeFn) {
-        let name = StringObj::new(name.to_string());
-        let function = Value::Obj(Obj::NativeFunction(NativeFunction::new(function).into()));
-        self.push(Value::Obj(Obj::String(name.clone().into())));
-        self.push(function.clone());
-        self.globals.insert(name, function);
+    /// Replaces the receiver on top of the stack with `method` bound to it.
+    fn bind_method(&mut self, method: Gc<Closure>) -> RuntimeResult<()> {
+        let bound_method = self.try_allocate(BoundMethod::new(self.peek(0), method))?;
         self.pop();
+        self.push(Value::obj(Obj::BoundMethod(bound_method)));
+        Ok(())
+    }
+
+    fn define_method(&mut self, name: Gc<StringObj>) {
+        if let (Some(Obj::Closure(method)), Some(Obj::Class(class))) = (self.peek(0).as_obj(), self.peek(1).as_obj()) {
+            class.methods.borrow_mut().insert(name, method);
+        }
         self.pop();
     }
+
+    fn capture_upvalue(&mut self, slot: usize) -> RuntimeResult<Gc<Upvalue>> {
+        // Open upvalues are kept sorted by stack slot, so the search can stop early.
+        let mut insert_at = self.open_upvalues.len();
+        for (i, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            if upvalue.slot == slot {
+                return Ok(*upvalue);
+            } else if upvalue.slot < slot {
+                break;
+            }
+            insert_at = i;
+        }
+
+        let upvalue = self.try_allocate(Upvalue::new(slot))?;
+        self.open_upvalues.insert(insert_at, upvalue);
+        Ok(upvalue)
+    }
+
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            if upvalue.slot < last {
+                break;
+            }
+
+            upvalue.closed.set(Some(self.stack[upvalue.slot]));
+            self.open_upvalues.pop();
+        }
+    }
+
+    pub(super) fn intern(&mut self, string: String) -> Gc<StringObj> {
+        self.intern_with_roots(string, |_| ())
+    }
+
+    /// Returns the interned string equal to `string`, allocating it if there isn't one yet.
+    pub(super) fn intern_with_roots(&mut self, string: String, mark_roots: impl FnOnce(&mut Heap)) -> Gc<StringObj> {
+        if let Some(interned) = self.strings.find_string(&string, hash_string(&string)) {
+            return interned;
+        }
+
+        let interned = self.allocate_with_roots(StringObj::new(string), mark_roots);
+        self.strings.set(interned, Value::NIL);
+        interned
+    }
+
+    pub(super) fn allocate<T: Object>(&mut self, object: T) -> Gc<T> {
+        self.allocate_with_roots(object, |_| ())
+    }
+
+    /// Allocates `object` on the heap, collecting garbage first if the heap has grown enough.
+    ///
+    /// `mark_roots` marks any roots the VM can't see itself, such as the functions still being
+    /// compiled. The object being allocated is traced too, since nothing else refers to it yet.
+    pub(super) fn allocate_with_roots<T: Object>(&mut self, object: T, mark_roots: impl FnOnce(&mut Heap)) -> Gc<T> {
+        if self.heap.should_collect() {
+            self.collect_garbage(|heap| {
+                mark_roots(heap);
+                object.trace(heap);
+            });
+        }
+
+        self.heap.alloc(object)
+    }
+
+    /// Like [`VM::allocate`], but fails instead of going over [`Limits::max_heap_bytes`].
+    fn try_allocate<T: Object>(&mut self, object: T) -> RuntimeResult<Gc<T>> {
+        self.reserve(Heap::size_of(&object), |heap| object.trace(heap))?;
+        Ok(self.allocate(object))
+    }
+
+    /// Checks that `bytes` more fit under [`Limits::max_heap_bytes`], collecting garbage to make
+    /// room if they don't. `mark_roots` marks whatever the caller holds that the VM can't see.
+    fn reserve(&mut self, bytes: usize, mark_roots: impl FnOnce(&mut Heap)) -> RuntimeResult<()> {
+        let Some(max) = self.limits.max_heap_bytes else {
+            return Ok(());
+        };
+        if self.heap.bytes_allocated() + self.shapes.bytes() + bytes > max {
+            self.collect_garbage(mark_roots);
+            if self.heap.bytes_allocated() + self.shapes.bytes() + bytes > max {
+                return Err(runtime_error!(self, "Out of memory: the heap is limited to {} bytes.", max));
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_garbage(&mut self, mark_roots: impl FnOnce(&mut Heap)) {
+        self.mark_roots();
+        mark_roots(&mut self.heap);
+        self.heap.trace_references();
+        self.mark_shapes();
+        self.strings.remove_white();
+        self.heap.sweep();
+        self.shapes.sweep();
+    }
+
+    /// Marks the shapes of the classes, instances and inline caches that survive, along with the
+    /// field names they hold.
+    fn mark_shapes(&mut self) {
+        for obj in self.heap.marked_objects() {
+            match obj {
+                Obj::Class(class) => self.shapes.mark(class.shape),
+                Obj::Instance(instance) => self.shapes.mark(instance.shape.get()),
+                Obj::Function(function) => {
+                    for cache in &function.chunk.caches {
+                        if let InlineCache::Transition { to, .. } = cache.get() {
+                            self.shapes.mark(to);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        self.shapes.mark_names(&mut self.heap);
+        self.heap.trace_references();
+    }
+
+    fn mark_roots(&mut self) {
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+
+        for frame in &self.frames {
+            self.heap.mark_object(Obj::Closure(frame.closure));
+        }
+
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(Obj::Upvalue(*upvalue));
+        }
+
+        for global in &self.globals {
+            self.heap.mark_object(Obj::String(global.name));
+            if let Some(value) = global.value {
+                self.heap.mark_value(value);
+            }
+        }
+
+        if let Some(init_string) = self.init_string {
+            self.heap.mark_object(Obj::String(init_string));
+        }
+
+        for value in self.handles.iter().flatten().chain(&self.temporaries) {
+            self.heap.mark_value(*value);
+        }
+    }
+
+    /// Defines a global native function that scripts can call with `arity` arguments.
+    pub fn define_native<F>(&mut self, name: &str, arity: u8, function: F)
+    where
+        F: FnMut(&mut VM, &[Value]) -> Result<Value, String> + 'static,
+    {
+        let native = NativeFunction::new(name.to_string(), arity, Box::new(function));
+        let name = self.intern(name.to_string());
+        self.push(Value::obj(Obj::String(name)));
+        let function = self.allocate(native);
+        self.push(Value::obj(Obj::NativeFunction(function)));
+        let slot = self.global_slot(name);
+        self.globals[slot].value = Some(self.peek(0));
+        self.pop();
+        self.pop();
+    }
+
+    /// Defines a global object named after the module, with a method for each of its natives.
+    pub fn register_module(&mut self, module: NativeModule) {
+        let name = self.intern(module.name);
+        self.push(Value::obj(Obj::String(name)));
+        let shape = self.shapes.root();
+        let class = self.allocate(Class::new(name, shape));
+        self.push(Value::obj(Obj::Class(class)));
+        let instance = self.allocate(Instance::new(class));
+        self.push(Value::obj(Obj::Instance(instance)));
+
+        for native in module.functions {
+            let function_name = self.intern(native.name.clone());
+            self.push(Value::obj(Obj::String(function_name)));
+            let function = self.allocate(native);
+            self.set_field(instance, function_name, Value::obj(Obj::NativeFunction(function)));
+            self.pop();
+        }
+
+        let slot = self.global_slot(name);
+        self.globals[slot].value = Some(self.peek(0));
+        self.pop();
+        self.pop();
+        self.pop();
+    }
+
+    /// Returns the slot of the global called `name`, adding an undefined one if there isn't one.
+    ///
+    /// Slots are never reused, so code compiled earlier, say on a previous REPL line, keeps
+    /// referring to the same variable.
+    pub(super) fn global_slot(&mut self, name: Gc<StringObj>) -> usize {
+        *self.global_slots.entry(name).or_insert_with(|| {
+            self.globals.push(Global { name, value: None });
+            self.globals.len() - 1
+        })
+    }
+
+    /// Returns the slot of the global called `name` without adding one.
+    pub(super) fn find_global_slot(&self, name: Gc<StringObj>) -> Option<usize> {
+        self.global_slots.get(&name).copied()
+    }
+
+    /// How many global slots have been handed out so far.
+    pub(super) fn global_count(&self) -> usize {
+        self.globals.len()
+    }
+
+    /// The name of every global slot, in slot order.
+    pub(super) fn global_names(&self) -> impl Iterator<Item = Gc<StringObj>> + '_ {
+        self.globals.iter().map(|global| global.name)
+    }
 }
@@ -0,0 +1,92 @@
+//! Compile errors rendered like rustc's, quoting the offending line with the span underlined.
+//!
+//! ```text
+//! Error at ';': Expect expression.
+//!  --> script.lox:3:11
+//!   |
+//! 3 | print 1 + ;
+//!   |           ^
+//! ```
+
+use std::io::{self, IsTerminal, Write};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A range of bytes in the source, as `start..end`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+/// The source a diagnostic points into, and the name to show for it.
+#[derive(Clone, Copy, Debug)]
+pub struct Source<'a> {
+    pub name: &'a str,
+    pub text: &'a str,
+}
+
+impl<'a> Source<'a> {
+    pub fn new(name: &'a str, text: &'a str) -> Self {
+        Source { name, text }
+    }
+}
+
+/// Whether diagnostics written to stderr should be colored: only on a terminal, and only if the
+/// user hasn't asked for no color with `NO_COLOR`.
+pub fn stderr_supports_color() -> bool {
+    io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// Writes the error `Error{location}: {message}` for `span` in `source`.
+pub fn render(
+    out: &mut dyn Write,
+    source: &Source,
+    span: Span,
+    location: &str,
+    message: &str,
+    color: bool,
+) -> io::Result<()> {
+    let paint = |style: &'static str| if color { style } else { "" };
+    let (red, blue, bold, reset) = (paint(RED), paint(BLUE), paint(BOLD), paint(RESET));
+
+    let text = source.text;
+    // An error at the end of a file that ends in a newline belongs on the last line, not after it.
+    let mut start = span.start.min(text.len());
+    if start == text.len() && text.ends_with('\n') {
+        start -= 1;
+    }
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+
+    let line_start = text[..start].rfind('\n').map_or(0, |newline| newline + 1);
+    let line_end = text[start..].find('\n').map_or(text.len(), |newline| start + newline);
+    let line = text[..start].matches('\n').count() + 1;
+    let before = &text[line_start..start];
+    let column = before.chars().count() + 1;
+
+    // Underline the span up to the end of its first line, with at least one caret.
+    let end = span.end.clamp(start, line_end);
+    let width = text.get(start..end).map_or(0, |spanned| spanned.chars().count()).max(1);
+    // Keep tabs in the padding so the carets line up however the terminal renders them.
+    let padding: String = before.chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+
+    let gutter = " ".repeat(line.to_string().len());
+    let quoted = text[line_start..line_end].trim_end_matches('\r');
+    writeln!(out, "{red}Error{reset}{bold}{location}: {message}{reset}")?;
+    writeln!(out, "{gutter}{blue}-->{reset} {}:{line}:{column}", source.name)?;
+    writeln!(out, "{gutter} {blue}|{reset}")?;
+    writeln!(out, "{blue}{line} |{reset} {quoted}")?;
+    writeln!(out, "{gutter} {blue}|{reset} {padding}{red}{}{reset}", "^".repeat(width))?;
+    writeln!(out)
+}
@@ -1,2 +1,4 @@
 pub mod treewalk;
 pub mod bytecode;
+pub mod diagnostic;
+pub mod output;
@@ -1,30 +1,95 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
 use std::process::ExitCode;
 
-use crafting_interpreters::{bytecode, treewalk};
-use bytecode::{repl, run_file};
-use bytecode::vm::{VM, InterpretResult};
+use crafting_interpreters::bytecode;
+use bytecode::{emit_bytecode, repl, run_file};
+use bytecode::vm::{VM, VMOptions, InterpretResult};
 
-fn main() -> ExitCode {
-    let mut vm = VM::new();
+const USAGE: &str =
+    "Usage: jlox [-O] [--emit-bytecode out.loxc] [--trace-execution] [--print-code] [--trace-file path] [--cache-stats] [--fuel n] [script]";
+
+#[derive(Default)]
+struct Options {
+    script: Option<String>,
+    /// Where to write the compiled script instead of running it.
+    emit_bytecode: Option<String>,
+    vm: VMOptions,
+    /// Where to write traces and disassembly instead of stderr.
+    trace_file: Option<String>,
+    /// Print how often the inline caches hit once the script is done.
+    cache_stats: bool,
+    /// Stop after running this many instructions.
+    fuel: Option<u64>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Options> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O" => options.vm.optimize = true,
+            "--emit-bytecode" => options.emit_bytecode = Some(args.next()?),
+            "--trace-execution" => options.vm.trace_execution = true,
+            "--print-code" => options.vm.print_code = true,
+            "--trace-file" => options.trace_file = Some(args.next()?),
+            "--cache-stats" => options.cache_stats = true,
+            "--fuel" => options.fuel = Some(args.next()?.parse().ok()?),
+            _ if arg.starts_with("--") || options.script.is_some() => return None,
+            _ => options.script = Some(arg),
+        }
+    }
+
+    if options.emit_bytecode.is_some() && options.script.is_none() {
+        return None;
+    }
+    Some(options)
+}
 
-    let args = std::env::args().collect::<Vec<_>>();
+fn main() -> ExitCode {
+    let Some(options) = parse_args(std::env::args().skip(1)) else {
+        println!("{}", USAGE);
+        return ExitCode::from(64);
+    };
 
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => match run_file(&mut vm, &args[1]) {
-            Ok(InterpretResult::CompileError) => return ExitCode::from(65),
-            Ok(InterpretResult::RuntimeError) => return ExitCode::from(70),
-            Err(_) => {
-                println!("Could not open file \"{}\".", &args[1]);
+    let mut vm = VM::with_options(options.vm);
+    vm.set_fuel(options.fuel);
+    if let Some(path) = &options.trace_file {
+        match File::create(path) {
+            Ok(file) => vm.set_trace_output(BufWriter::new(file)),
+            Err(error) => {
+                eprintln!("Could not create trace file \"{}\": {}", path, error);
                 return ExitCode::from(74);
             }
-            _ => (),
-        },
-        _ => {
-            println!("Usage: jlox [script]");
-            return ExitCode::from(64);
         }
     }
 
-    ExitCode::SUCCESS
+    let Some(script) = options.script else {
+        repl(&mut vm);
+        return ExitCode::SUCCESS;
+    };
+
+    let result = match &options.emit_bytecode {
+        Some(output) => emit_bytecode(&mut vm, &script, output),
+        None => run_file(&mut vm, &script),
+    };
+    if options.cache_stats {
+        let stats = vm.cache_stats();
+        eprintln!("property cache: {} hits, {} misses", stats.property_hits, stats.property_misses);
+        eprintln!("invoke cache: {} hits, {} misses", stats.invoke_hits, stats.invoke_misses);
+    }
+    match result {
+        Ok(InterpretResult::CompileError(_)) => ExitCode::from(65),
+        Ok(InterpretResult::RuntimeError(_) | InterpretResult::OutOfFuel | InterpretResult::Interrupted(_)) => {
+            ExitCode::from(70)
+        }
+        Ok(InterpretResult::Ok) => ExitCode::SUCCESS,
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+            eprintln!("Could not load \"{}\": {}", &script, error);
+            ExitCode::from(65)
+        }
+        Err(_) => {
+            println!("Could not open file \"{}\".", &script);
+            ExitCode::from(74)
+        }
+    }
 }
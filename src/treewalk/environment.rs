@@ -1,14 +1,10 @@
 use std::collections::hash_map::{Entry, HashMap};
 use std::mem;
 
-use super::report;
+use super::format_error;
 use crate::treewalk::interpreter::{RuntimeError, RuntimeResult};
 use crate::treewalk::token::{Literal, Token};
 
-fn error(token: &Token, message: &str) {
-    report(token.line, &format!(" at \"{}\"", token.lexeme), message);
-}
-
 #[derive(Default, Debug, Clone)]
 pub struct Environment {
     pub enclosing: Option<Box<Environment>>,
@@ -63,7 +59,6 @@ impl Environment {
     }
 
     fn error(&self, token: &Token, message: &str) -> RuntimeError {
-        error(token, message);
-        RuntimeError::Err
+        RuntimeError::Err(format_error(token.line, &format!(" at \"{}\"", token.lexeme), message))
     }
 }
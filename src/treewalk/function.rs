@@ -71,7 +71,7 @@ impl Callable for Function {
                 Err(error) => {
                     self.closure.pop();
                     return match error {
-                        RuntimeError::Err => Err(error),
+                        RuntimeError::Err(_) => Err(error),
                         RuntimeError::Return(value) => Ok(value),
                     };
                 },
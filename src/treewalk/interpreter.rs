@@ -2,19 +2,19 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use substring::Substring;
 
-use super::report;
+use std::io::{self, Write};
+
+use super::format_error;
+use crate::diagnostic;
 use crate::treewalk::environment::Environment;
 use crate::treewalk::expr::Expr;
 use crate::treewalk::function::{Callable, Function, NativeFunction};
 use crate::treewalk::stmt::Stmt;
 use crate::treewalk::token::{Literal, Token, TokenType};
 
-fn error(token: &Token, message: &str) {
-    report(token.line, &format!(" at \"{}\"", token.lexeme), message);
-}
-
 pub enum RuntimeError {
-    Err,
+    /// A runtime error, with the message to report.
+    Err(String),
     Return(Literal),
 }
 
@@ -22,6 +22,16 @@ pub type RuntimeResult<T> = Result<T, RuntimeError>;
 
 pub struct Interpreter {
     pub environment: Environment,
+    output: Box<dyn Write>,
+    pub(super) diagnostics: Box<dyn Write>,
+    /// Whether to color compile errors, which only happens while they go to a terminal.
+    pub(super) color: bool,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interpreter {
@@ -46,15 +56,30 @@ impl Interpreter {
 
         Interpreter {
             environment: globals,
+            output: Box::new(io::stdout()),
+            diagnostics: Box::new(io::stderr()),
+            color: diagnostic::stderr_supports_color(),
         }
     }
 
+    /// Sets where `print` statements write to. Defaults to stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Sets where errors are reported. Defaults to stderr.
+    pub fn set_diagnostics(&mut self, diagnostics: impl Write + 'static) {
+        self.diagnostics = Box::new(diagnostics);
+        self.color = false;
+    }
+
     pub fn interpret(&mut self, statements: &[Stmt]) -> RuntimeResult<()> {
         for statement in statements {
             match statement {
                 Stmt::Print(value) => {
                     let value = self.evaluate(value)?;
-                    println!("{}", self.stringify(value))
+                    let text = self.stringify(value);
+                    writeln!(self.output, "{}", text).map_err(|error| RuntimeError::Err(error.to_string()))?;
                 }
                 Stmt::Return(_, value) => {
                     let value = match **value {
@@ -332,7 +357,6 @@ impl Interpreter {
     }
 
     fn error(&self, token: &Token, message: &str) -> RuntimeError {
-        error(token, message);
-        RuntimeError::Err
+        RuntimeError::Err(format_error(token.line, &format!(" at \"{}\"", token.lexeme), message))
     }
 }
@@ -11,13 +11,16 @@ use std::fs;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
 
-use interpreter::Interpreter;
+use crate::diagnostic::{self, Source, Span};
+use interpreter::RuntimeError;
 use parser::Parser;
 use scanner::Scanner;
 
+pub use interpreter::Interpreter;
+
 pub fn run_file(path: &str) {
     let bytes = fs::read_to_string(path).unwrap();
-    run(&bytes);
+    run_source(&mut Interpreter::new(), Source::new(path, &bytes));
 }
 
 // TODO: Fix this
@@ -27,25 +30,39 @@ pub fn run_prompt() {
 
     println!("Lox Interactive REPL\n");
 
+    let mut interpreter = Interpreter::new();
     loop {
         print!("> ");
         io::stdout().flush().unwrap();
         let mut line = String::new();
-        reader.read_line(&mut line);
-        run(&line);
+        if reader.read_line(&mut line).is_err() {
+            break;
+        }
+        run_source(&mut interpreter, Source::new("<repl>", &line));
     }
 }
 
-fn run(source: &str) {
-    let mut scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
-    let mut parser = Parser::new(tokens);
-    let statements = parser.parse();
-    let mut interpreter = Interpreter::new();
+/// Runs `source`, writing program output and errors to the interpreter's sinks.
+pub fn run(interpreter: &mut Interpreter, source: &str) {
+    run_source(interpreter, Source::new("<script>", source));
+}
+
+/// Like [`run`], but compile errors name the file `source` came from.
+pub fn run_source(interpreter: &mut Interpreter, source: Source) {
+    let diagnostics = &mut *interpreter.diagnostics;
+    let color = interpreter.color;
+    let tokens = Scanner::new(source, diagnostics, color).scan_tokens();
+    let statements = Parser::new(tokens, source, diagnostics, color).parse();
+
+    if let Err(RuntimeError::Err(message)) = interpreter.interpret(&statements) {
+        let _ = writeln!(interpreter.diagnostics, "{message}");
+    }
+}
 
-    interpreter.interpret(&statements);
+fn format_error(line: u32, location: &str, message: &str) -> String {
+    format!("[line {line}] Error{location}: {message}")
 }
 
-fn report(line: u32, location: &str, message: &str) {
-    eprintln!("[line {line}] Error{location}: {message}");
+fn report(diagnostics: &mut dyn Write, source: &Source, color: bool, span: Span, location: &str, message: &str) {
+    let _ = diagnostic::render(diagnostics, source, span, location, message, color);
 }
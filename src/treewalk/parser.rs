@@ -1,4 +1,8 @@
+use std::cell::RefCell;
+use std::io::Write;
+
 use super::report;
+use crate::diagnostic::Source;
 use crate::treewalk::expr::Expr;
 use crate::treewalk::stmt::Stmt;
 use crate::treewalk::token::{Literal, Token, TokenType};
@@ -8,17 +12,12 @@ pub struct ParseError;
 
 type ParseResult<T> = Result<T, ParseError>;
 
-fn error(token: &Token, message: &str) {
-    if token.r#type == TokenType::Eof {
-        report(token.line, " at end", message);
-    } else {
-        report(token.line, &format!(" at \"{}\"", token.lexeme), message);
-    }
-}
-
-pub struct Parser {
+pub struct Parser<'a> {
     tokens: Vec<Token>,
     current: usize,
+    diagnostics: RefCell<&'a mut dyn Write>,
+    source: Source<'a>,
+    color: bool,
 }
 
 // TODO: In C, a block is a statement form that allows you to pack a series of statements where a
@@ -33,9 +32,9 @@ pub struct Parser {
 // Report that as an error, but also parse and discard a right-hand operand with the appropriate
 // precedence.
 // TODO: Move tokens into expression tree, don't clone them.
-impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens, current: 0 }
+impl<'a> Parser<'a> {
+    pub fn new(tokens: Vec<Token>, source: Source<'a>, diagnostics: &'a mut dyn Write, color: bool) -> Parser<'a> {
+        Parser { tokens, current: 0, diagnostics: RefCell::new(diagnostics), source, color }
     }
 
     pub fn parse(&mut self) -> Vec<Stmt> {
@@ -493,7 +492,13 @@ impl Parser {
     }
 
     fn error(&self, token: &Token, message: &str) -> ParseError {
-        error(token, message);
+        let mut diagnostics = self.diagnostics.borrow_mut();
+        let location = if token.r#type == TokenType::Eof {
+            " at end".to_string()
+        } else {
+            format!(" at \"{}\"", token.lexeme)
+        };
+        report(*diagnostics, &self.source, self.color, token.span, &location, message);
         ParseError
     }
 
@@ -1,17 +1,16 @@
 use std::collections::HashMap;
+use std::io::Write;
 
 use lazy_static::lazy_static;
-use substring::Substring;
 
 use super::report;
+use crate::diagnostic::{Source, Span};
 use crate::treewalk::token::{Literal, Token, TokenType};
 
-fn error(line: u32, message: &str) {
-    report(line, "", message);
-}
-
-pub struct Scanner {
-    source: Box<str>,
+pub struct Scanner<'a> {
+    diagnostics: &'a mut dyn Write,
+    source: Source<'a>,
+    color: bool,
     tokens: Vec<Token>,
     start: usize,
     current: usize,
@@ -41,10 +40,12 @@ lazy_static! {
     };
 }
 
-impl Scanner {
-    pub fn new(source: &str) -> Scanner {
+impl<'a> Scanner<'a> {
+    pub fn new(source: Source<'a>, diagnostics: &'a mut dyn Write, color: bool) -> Scanner<'a> {
         Scanner {
-            source: source.into(),
+            diagnostics,
+            source,
+            color,
             tokens: Vec::new(),
             start: 0,
             current: 0,
@@ -59,6 +60,7 @@ impl Scanner {
             self.scan_token();
         }
 
+        self.start = self.current;
         self.add_token(TokenType::Eof);
         self.tokens.clone()
     }
@@ -130,18 +132,23 @@ impl Scanner {
                 } else if c.is_ascii_alphabetic() {
                     self.identifier();
                 } else {
-                    error(self.line, "Unexpected character");
+                    self.error("Unexpected character");
                 }
             }
         }
     }
 
+    fn error(&mut self, message: &str) {
+        let span = Span::new(self.start, self.current);
+        report(self.diagnostics, &self.source, self.color, span, "", message);
+    }
+
     fn identifier(&mut self) {
         while self.peek().is_ascii_alphanumeric() {
             self.advance();
         }
 
-        let text = self.source.substring(self.start, self.current);
+        let text = &self.source.text[self.start..self.current];
         let r#type = KEYWORDS.get(text).unwrap_or(&TokenType::Identifier);
         self.add_token(*r#type);
     }
@@ -162,8 +169,7 @@ impl Scanner {
         self.add_token_with_literal(
             TokenType::Number,
             Literal::Number(
-                self.source
-                    .substring(self.start, self.current)
+                self.source.text[self.start..self.current]
                     .parse()
                     .unwrap(),
             ),
@@ -202,7 +208,7 @@ impl Scanner {
         }
 
         if self.is_at_end() {
-            error(self.line, "Unterminated string.");
+            self.error("Unterminated string.");
             return;
         }
 
@@ -210,41 +216,34 @@ impl Scanner {
         self.advance();
 
         // Trim the surrounding quotes.
-        let value = self.source.substring(self.start + 1, self.current - 1);
+        let value = &self.source.text[self.start + 1..self.current - 1];
         self.add_token_with_literal(TokenType::String, Literal::String(value.into()));
     }
 
     fn matches(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.current += expected.len_utf8();
 
         true
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current).unwrap()
+        self.source.text[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current + 1).unwrap()
+        self.source.text[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        let c = self.peek();
+        if !self.is_at_end() {
+            self.current += c.len_utf8();
+        }
+        c
     }
 
     fn add_token(&mut self, r#type: TokenType) {
@@ -252,12 +251,13 @@ impl Scanner {
     }
 
     fn add_token_with_literal(&mut self, r#type: TokenType, literal: Literal) {
-        let text = self.source.substring(self.start, self.current);
+        let text = &self.source.text[self.start..self.current];
+        let span = Span::new(self.start, self.current);
         self.tokens
-            .push(Token::new(r#type, text, literal, self.line));
+            .push(Token::new(r#type, text, literal, self.line, span));
     }
 
     fn is_at_end(&self) -> bool {
-        self.current >= self.source.len()
+        self.current >= self.source.text.len()
     }
 }
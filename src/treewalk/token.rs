@@ -1,3 +1,4 @@
+use crate::diagnostic::Span;
 use crate::treewalk::function::{Function, NativeFunction};
 
 // TODO: Implement C-style comma operator
@@ -60,15 +61,17 @@ pub struct Token {
     pub lexeme: Box<str>,
     pub literal: Literal,
     pub line: u32,
+    pub span: Span,
 }
 
 impl Token {
-    pub fn new(r#type: TokenType, lexeme: &str, literal: Literal, line: u32) -> Token {
+    pub fn new(r#type: TokenType, lexeme: &str, literal: Literal, line: u32, span: Span) -> Token {
         Token {
             r#type,
             lexeme: lexeme.into(),
             literal,
             line,
+            span,
         }
     }
 }
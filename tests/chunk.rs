@@ -0,0 +1,34 @@
+use crafting_interpreters::bytecode::chunk::{Chunk, OpCode, Position};
+
+fn at(line: u32, column: u32) -> Position {
+    Position { line, column }
+}
+
+fn chunk_with(positions: &[Position]) -> Chunk {
+    let mut chunk = Chunk::new();
+    for position in positions {
+        chunk.write(OpCode::Nil as u8, *position);
+    }
+    chunk
+}
+
+#[test]
+fn positions_are_found_for_every_offset() {
+    let positions = [at(1, 1), at(1, 1), at(1, 5), at(2, 3), at(2, 3), at(2, 3), at(4, 1), at(1, 1)];
+    let chunk = chunk_with(&positions);
+
+    assert_eq!(chunk.byte_positions(), positions);
+    for (offset, position) in positions.iter().enumerate() {
+        assert_eq!(chunk.get_position(offset), *position, "at offset {}", offset);
+    }
+}
+
+#[test]
+fn truncating_drops_the_positions_of_dropped_code() {
+    let mut chunk = chunk_with(&[at(1, 1), at(2, 1), at(2, 1), at(3, 1)]);
+    chunk.truncate(2);
+    chunk.write(OpCode::Return as u8, at(5, 2));
+
+    assert_eq!(chunk.byte_positions(), [at(1, 1), at(2, 1), at(5, 2)]);
+    assert_eq!(chunk.get_position(2), at(5, 2));
+}
@@ -0,0 +1,10 @@
+use crafting_interpreters::bytecode::vm::VM;
+use crafting_interpreters::output::SharedBuffer;
+
+/// A VM whose `print` output goes to the returned buffer.
+pub fn vm_with_output() -> (VM, SharedBuffer) {
+    let output = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    (vm, output)
+}
@@ -0,0 +1,28 @@
+use crafting_interpreters::bytecode::chunk::{Chunk, MAX_LONG_CONSTANT};
+use crafting_interpreters::bytecode::value::Value;
+use crafting_interpreters::bytecode::vm::{InterpretResult, VM};
+
+#[test]
+fn chunk_refuses_constants_past_the_long_operand() {
+    let mut chunk = Chunk::new();
+    chunk.constants.resize(MAX_LONG_CONSTANT, Value::NIL);
+
+    assert_eq!(chunk.add_constant(Value::number(1.0)), Some(MAX_LONG_CONSTANT));
+    assert_eq!(chunk.add_constant(Value::number(2.0)), None);
+    assert_eq!(chunk.constants.len(), MAX_LONG_CONSTANT + 1);
+}
+
+#[test]
+#[ignore = "compiles 16 million constants, which takes about a minute without --release"]
+fn too_many_constants_is_a_compile_error() {
+    // Every literal gets a constant of its own, one more than the long opcodes can address.
+    let source = "0;\n".repeat(MAX_LONG_CONSTANT + 2);
+
+    let mut vm = VM::new();
+    let InterpretResult::CompileError(diagnostics) = vm.interpret(&source) else {
+        panic!("expected a compile error");
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Too many constants in one chunk.");
+    assert_eq!(diagnostics[0].line, MAX_LONG_CONSTANT as u32 + 2);
+}
@@ -0,0 +1,40 @@
+use crafting_interpreters::bytecode::vm::VM;
+use crafting_interpreters::diagnostic::Source;
+use crafting_interpreters::output::SharedBuffer;
+use crafting_interpreters::treewalk::{self, Interpreter};
+
+fn vm_diagnostics(source: &str) -> String {
+    let diagnostics = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_diagnostics(diagnostics.clone());
+    let result = vm.interpret(source);
+    vm.report_with_source(&result, &Source::new("main.lox", source)).unwrap();
+    diagnostics.contents()
+}
+
+fn tree_walker_diagnostics(source: &str) -> String {
+    let diagnostics = SharedBuffer::new();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_diagnostics(diagnostics.clone());
+    treewalk::run_source(&mut interpreter, Source::new("main.lox", source));
+    diagnostics.contents()
+}
+
+#[test]
+fn both_engines_render_the_same_snippet() {
+    let source = "var answer = 42;\nvar nil = answer;\n";
+    let snippet = " --> main.lox:2:5\n  |\n2 | var nil = answer;\n  |     ^^^\n\n";
+
+    assert_eq!(vm_diagnostics(source), format!("Error at 'nil': Expect variable name.\n{}", snippet));
+    // The tree-walker has always quoted tokens with double quotes.
+    assert_eq!(tree_walker_diagnostics(source), format!("Error at \"nil\": Expect variable name.\n{}", snippet));
+}
+
+#[test]
+fn gutter_fits_the_line_number() {
+    let source = format!("{}var 1 = 2;\n", "\n".repeat(11));
+    let snippet = "  --> main.lox:12:5\n   |\n12 | var 1 = 2;\n   |     ^\n\n";
+
+    assert_eq!(vm_diagnostics(&source), format!("Error at '1': Expect variable name.\n{}", snippet));
+    assert_eq!(tree_walker_diagnostics(&source), format!("Error at \"1\": Expect variable name.\n{}", snippet));
+}
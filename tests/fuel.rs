@@ -0,0 +1,71 @@
+use crafting_interpreters::bytecode::vm::InterpretResult;
+
+mod common;
+
+use common::vm_with_output;
+
+const SOURCE: &str = "
+    fun fib(n) {
+      if (n < 2) return n;
+      return fib(n - 1) + fib(n - 2);
+    }
+    for (var i = 0; i < 12; i = i + 1) {
+      print fib(i);
+    }
+";
+
+#[test]
+fn script_runs_in_time_slices() {
+    let (mut vm, expected) = vm_with_output();
+    assert!(matches!(vm.interpret(SOURCE), InterpretResult::Ok));
+
+    let (mut vm, output) = vm_with_output();
+    vm.set_fuel(Some(100));
+    let mut result = vm.interpret(SOURCE);
+    let mut slices = 1;
+    while let InterpretResult::OutOfFuel = result {
+        vm.set_fuel(Some(100));
+        result = vm.resume();
+        slices += 1;
+    }
+    assert!(matches!(result, InterpretResult::Ok));
+    assert!(slices > 10, "ran in {} slices", slices);
+    assert_eq!(output.contents(), expected.contents());
+}
+
+#[test]
+fn fuel_counts_instructions() {
+    let (mut vm, _) = vm_with_output();
+    vm.set_fuel(Some(100));
+    // OpConstant, OpPrint, OpNil and OpReturn.
+    assert!(matches!(vm.interpret("print 1;"), InterpretResult::Ok));
+    assert_eq!(vm.fuel(), Some(96));
+
+    vm.set_fuel(None);
+    assert_eq!(vm.fuel(), None);
+}
+
+#[test]
+fn resume_without_a_suspended_script_does_nothing() {
+    let (mut vm, output) = vm_with_output();
+    assert!(matches!(vm.resume(), InterpretResult::Ok));
+    assert!(matches!(vm.interpret("print 1;"), InterpretResult::Ok));
+    assert!(matches!(vm.resume(), InterpretResult::Ok));
+    assert_eq!(output.contents(), "1\n");
+}
+
+#[test]
+fn running_out_in_a_host_call_is_an_error() {
+    let (mut vm, output) = vm_with_output();
+    assert!(matches!(vm.interpret("fun spin() { while (true) {} }"), InterpretResult::Ok));
+
+    vm.set_fuel(Some(1000));
+    let error = vm.call_global("spin", &[]).unwrap_err();
+    assert_eq!(error.message, "Out of fuel.");
+
+    // Nothing is left suspended, and the VM runs the next script as usual.
+    vm.set_fuel(None);
+    assert!(matches!(vm.resume(), InterpretResult::Ok));
+    assert!(matches!(vm.interpret("print \"after\";"), InterpretResult::Ok));
+    assert_eq!(output.contents(), "after\n");
+}
@@ -0,0 +1,71 @@
+use crafting_interpreters::bytecode::value::Value;
+use crafting_interpreters::bytecode::vm::{InterpretResult, VM};
+
+mod common;
+
+use common::vm_with_output;
+
+fn run(vm: &mut VM, source: &str) {
+    assert!(matches!(vm.interpret(source), InterpretResult::Ok), "{}", source);
+}
+
+fn runtime_error(vm: &mut VM, source: &str) -> String {
+    let InterpretResult::RuntimeError(error) = vm.interpret(source) else {
+        panic!("expected a runtime error from {}", source);
+    };
+    error.message
+}
+
+#[test]
+fn globals_last_across_scripts() {
+    let (mut vm, output) = vm_with_output();
+    run(&mut vm, "var a = 1;");
+    run(&mut vm, "fun show() { print a; }");
+    run(&mut vm, "show(); a = 2; show();");
+    run(&mut vm, "var a = 3; show();");
+    assert_eq!(output.contents(), "1\n2\n3\n");
+}
+
+#[test]
+fn functions_see_globals_defined_after_them() {
+    let (mut vm, output) = vm_with_output();
+    run(&mut vm, "fun get() { return later; }");
+    assert_eq!(runtime_error(&mut vm, "print get();"), "Undefined variable 'later'.");
+    run(&mut vm, "var later = \"defined\"; print get();");
+    assert_eq!(output.contents(), "defined\n");
+}
+
+#[test]
+fn undefined_globals_are_named_in_errors() {
+    let (mut vm, _) = vm_with_output();
+    assert_eq!(runtime_error(&mut vm, "print missing;"), "Undefined variable 'missing'.");
+    assert_eq!(runtime_error(&mut vm, "unset = 1;"), "Undefined variable 'unset'.");
+    // The failed assignment didn't define it either.
+    assert_eq!(runtime_error(&mut vm, "print unset;"), "Undefined variable 'unset'.");
+}
+
+#[test]
+fn natives_can_be_defined_after_scripts_use_them() {
+    let (mut vm, output) = vm_with_output();
+    run(&mut vm, "fun ask() { return host(); }");
+    vm.define_native("host", 0, |_, _| Ok(Value::number(42.0)));
+    run(&mut vm, "print ask();");
+    assert_eq!(output.contents(), "42\n");
+}
+
+#[test]
+fn many_globals() {
+    // Past 256 globals the global instructions need their long forms.
+    let (mut vm, output) = vm_with_output();
+    let mut source = String::new();
+    for i in 0..300 {
+        source += &format!("var g{} = {};\n", i, i);
+    }
+    source += "var sum = 0;\n";
+    for i in 0..300 {
+        source += &format!("sum = sum + g{};\n", i);
+    }
+    source += "g299 = -1;\nprint sum;\nprint g299;\n";
+    run(&mut vm, &source);
+    assert_eq!(output.contents(), "44850\n-1\n");
+}
@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crafting_interpreters::bytecode::native::IntoLox;
+use crafting_interpreters::bytecode::value::Value;
+use crafting_interpreters::bytecode::vm::{Handle, InterpretResult, VM};
+use crafting_interpreters::output::SharedBuffer;
+
+fn vm_running(source: &str) -> (VM, SharedBuffer) {
+    let output = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+    (vm, output)
+}
+
+#[test]
+fn call_global_returns_the_result() {
+    let (mut vm, output) = vm_running(
+        "
+        fun add(a, b) { return a + b; }
+        fun greet(name) { print \"hello \" + name; }
+        ",
+    );
+
+    let sum = vm.call_global("add", &[Value::number(1.0), Value::number(2.0)]).unwrap();
+    assert_eq!(vm.get::<f64>(&sum), Ok(3.0));
+
+    let name = "host".into_lox(&mut vm);
+    let name = vm.retain(name);
+    let name = (&name).into_lox(&mut vm);
+    let result = vm.call_global("greet", &[name]).unwrap();
+    assert_eq!(vm.get::<Option<f64>>(&result), Ok(None));
+    assert_eq!(output.contents(), "hello host\n");
+}
+
+#[test]
+fn results_stay_alive_until_released() {
+    let (mut vm, _) = vm_running("fun greeting(name) { return \"hello \" + name; }");
+    let name = "host".into_lox(&mut vm);
+    let greeting = vm.call_global("greeting", &[name]).unwrap();
+
+    // Enough garbage to collect a few times over.
+    let source = "for (var i = 0; i < 10000; i = i + 1) { var s = \"garbage \" + \"string\"; }";
+    assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+    assert_eq!(vm.get::<String>(&greeting).as_deref(), Ok("hello host"));
+    vm.release(greeting);
+}
+
+#[test]
+fn call_global_reports_errors_and_stays_usable() {
+    let (mut vm, _) = vm_running(
+        "
+        fun fail() { return nil + 1; }
+        fun one() { return 1; }
+        ",
+    );
+
+    let error = vm.call_global("missing", &[]).unwrap_err();
+    assert_eq!(error.message, "Undefined variable 'missing'.");
+    let error = vm.call_global("fail", &[]).unwrap_err();
+    assert_eq!(error.message, "Operands must be two numbers or two strings.");
+    let error = vm.call_global("one", &[Value::NIL]).unwrap_err();
+    assert_eq!(error.message, "Expected 0 arguments but got 1.");
+
+    let one = vm.call_global("one", &[]).unwrap();
+    assert_eq!(vm.get::<f64>(&one), Ok(1.0));
+    assert!(matches!(vm.interpret("print one();"), InterpretResult::Ok));
+}
+
+#[test]
+fn call_handle_calls_closures_a_script_hands_back() {
+    let output = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    let callbacks: Rc<RefCell<Vec<Handle>>> = Default::default();
+    let registered = Rc::clone(&callbacks);
+    vm.define_native("onTick", 1, move |vm, args| {
+        registered.borrow_mut().push(vm.retain(args[0]));
+        Ok(Value::NIL)
+    });
+
+    let source = "
+        var ticks = 0;
+        fun counter(step) {
+          fun tick() {
+            ticks = ticks + step;
+            return ticks;
+          }
+          return tick;
+        }
+        onTick(counter(1));
+        onTick(counter(10));
+    ";
+    assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+
+    let callbacks = callbacks.take();
+    assert_eq!(callbacks.len(), 2);
+    for callback in &callbacks {
+        vm.call_handle(callback, &[]).unwrap();
+    }
+    let last = vm.call_handle(&callbacks[0], &[]).unwrap();
+    assert_eq!(vm.get::<f64>(&last), Ok(12.0));
+
+    for callback in callbacks {
+        vm.release(callback);
+    }
+    assert!(matches!(vm.interpret("print ticks;"), InterpretResult::Ok));
+    assert_eq!(output.contents(), "12\n");
+}
+
+#[test]
+fn natives_can_call_back_into_the_script() {
+    let (mut vm, output) = vm_running("fun double(x) { return x * 2; }");
+    vm.define_native("quadruple", 1, |vm, args| {
+        let twice = vm.call_global("double", &[args[0]]).map_err(|error| error.message)?;
+        let twice_value = (&twice).into_lox(vm);
+        let result = vm.call_global("double", &[twice_value]).map_err(|error| error.message)?;
+        let result_value = vm.get::<f64>(&result);
+        vm.release(twice);
+        vm.release(result);
+        Ok(result_value?.into_lox(vm))
+    });
+
+    assert!(matches!(vm.interpret("print quadruple(3);"), InterpretResult::Ok));
+    assert_eq!(output.contents(), "12\n");
+}
@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crafting_interpreters::bytecode::value::Value;
+use crafting_interpreters::bytecode::vm::InterpretResult;
+
+mod common;
+
+use common::vm_with_output;
+
+#[test]
+fn interrupt_from_another_thread_stops_a_loop() {
+    let (mut vm, output) = vm_with_output();
+    let handle = vm.interrupt_handle();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let interrupter = {
+        let stopped = Arc::clone(&stopped);
+        // A request made before the script starts is dropped, so keep asking until it stops.
+        thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                handle.interrupt();
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+
+    let result = vm.interpret("while (true) {}");
+    stopped.store(true, Ordering::SeqCst);
+    interrupter.join().unwrap();
+    let InterpretResult::Interrupted(error) = result else {
+        panic!("expected the loop to be interrupted");
+    };
+    assert_eq!(error.message, "Interrupted.");
+    assert!(error.interrupted);
+
+    assert!(matches!(vm.interpret("print \"after\";"), InterpretResult::Ok));
+    assert_eq!(output.contents(), "after\n");
+}
+
+#[test]
+fn native_can_interrupt_the_script_calling_it() {
+    let (mut vm, output) = vm_with_output();
+    let handle = vm.interrupt_handle();
+    vm.define_native("stop", 0, move |_, _| {
+        handle.interrupt();
+        Ok(Value::NIL)
+    });
+
+    let result = vm.interpret("stop(); for (var i = 0; i < 10; i = i + 1) {} print \"missed\";");
+    assert!(matches!(result, InterpretResult::Interrupted(_)));
+    assert_eq!(output.contents(), "");
+}
+
+#[test]
+fn request_made_before_a_script_starts_is_dropped() {
+    let (mut vm, output) = vm_with_output();
+
+    vm.interrupt_handle().interrupt();
+    assert!(matches!(vm.interpret("fun f() {} f(); print \"ran\";"), InterpretResult::Ok));
+    assert_eq!(output.contents(), "ran\n");
+}
+
+#[test]
+fn request_made_before_a_host_call_is_dropped() {
+    let (mut vm, _) = vm_with_output();
+    let source = "
+        fun twice(x) { return x * 2; }
+        fun adder(n) {
+          fun add(x) { return x + n; }
+          return add;
+        }
+    ";
+    assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+
+    vm.interrupt_handle().interrupt();
+    let result = vm.call_global("twice", &[Value::number(2.0)]).unwrap();
+    assert_eq!(vm.get::<f64>(&result), Ok(4.0));
+
+    let add = vm.call_global("adder", &[Value::number(1.0)]).unwrap();
+    vm.interrupt_handle().interrupt();
+    let result = vm.call_handle(&add, &[Value::number(2.0)]).unwrap();
+    assert_eq!(vm.get::<f64>(&result), Ok(3.0));
+}
@@ -0,0 +1,81 @@
+use crafting_interpreters::bytecode::error::RuntimeError;
+use crafting_interpreters::bytecode::vm::{InterpretResult, Limits, VM};
+use crafting_interpreters::output::SharedBuffer;
+
+fn run(source: &str, limits: Limits) -> (String, Result<(), RuntimeError>) {
+    let output = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    vm.set_limits(limits);
+    let result = match vm.interpret(source) {
+        InterpretResult::Ok => Ok(()),
+        InterpretResult::RuntimeError(error) => Err(error),
+        _ => panic!("expected the script to run"),
+    };
+    (output.contents(), result)
+}
+
+fn heap_limit(bytes: usize) -> Limits {
+    Limits {
+        max_heap_bytes: Some(bytes),
+        ..Limits::default()
+    }
+}
+
+#[test]
+fn heap_limit_stops_a_growing_program() {
+    let source = "
+        class Node {}
+        var head = nil;
+        while (true) {
+          var node = Node();
+          node.next = head;
+          head = node;
+        }
+    ";
+
+    let (_, result) = run(source, heap_limit(64 * 1024));
+    assert_eq!(result.unwrap_err().message, "Out of memory: the heap is limited to 65536 bytes.");
+}
+
+#[test]
+fn heap_limit_counts_fields_and_shapes() {
+    // Every field gets a shape of its own, which holds the slots of all the fields before it, so
+    // the shapes outgrow the limit long before the objects do.
+    let mut source = String::from("class Bag {}\nvar bag = Bag();\n");
+    for i in 0..2000 {
+        source += &format!("bag.field{} = {};\n", i, i);
+    }
+    source += "print \"done\";\n";
+
+    let (output, result) = run(&source, Limits::default());
+    assert!(result.is_ok());
+    assert_eq!(output, "done\n");
+
+    let (output, result) = run(&source, heap_limit(4 * 1024 * 1024));
+    assert_eq!(result.unwrap_err().message, "Out of memory: the heap is limited to 4194304 bytes.");
+    assert_eq!(output, "");
+}
+
+#[test]
+fn default_limits_allow_255_nested_calls() {
+    let source = "
+        var depth = 0;
+        fun nest() {
+          depth = depth + 1;
+          nest();
+        }
+        nest();
+    ";
+
+    let output = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    let InterpretResult::RuntimeError(error) = vm.interpret(source) else {
+        panic!("expected a stack overflow");
+    };
+    assert_eq!(error.message, "Stack overflow.");
+
+    vm.interpret("print depth;");
+    assert_eq!(output.contents(), "255\n");
+}
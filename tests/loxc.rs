@@ -0,0 +1,240 @@
+use crafting_interpreters::bytecode::chunk::{OpCode, Position};
+use crafting_interpreters::bytecode::compiler::compile;
+use crafting_interpreters::bytecode::error::LoadError;
+use crafting_interpreters::bytecode::loxc::{self, MAGIC, MAX_DEPTH, VERSION};
+use crafting_interpreters::bytecode::memory::Heap;
+use crafting_interpreters::bytecode::object::{Function, Obj};
+use crafting_interpreters::bytecode::value::Value;
+use crafting_interpreters::bytecode::vm::{InterpretResult, VM};
+use crafting_interpreters::output::SharedBuffer;
+
+fn run_source(source: &str) -> String {
+    let output = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+    output.contents()
+}
+
+fn write_source(source: &str) -> Vec<u8> {
+    let mut vm = VM::new();
+    let function = compile(source, &mut vm).expect("the script should compile");
+    loxc::write(&function, &vm)
+}
+
+fn run_bytes(bytes: &[u8]) -> String {
+    let output = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    let function = loxc::read(bytes, &mut vm).expect("the script should load");
+    assert!(matches!(vm.interpret_function(function), InterpretResult::Ok));
+    output.contents()
+}
+
+fn assert_round_trips(source: &str) {
+    let expected = run_source(source);
+    assert!(!expected.is_empty());
+    assert_eq!(run_bytes(&write_source(source)), expected);
+}
+
+/// Writes a script whose code is `code`, with `constants`.
+fn write_code(code: &[u8], constants: &[Value]) -> Vec<u8> {
+    let mut function = Function::new();
+    for byte in code {
+        function.chunk.write(*byte, Position { line: 1, column: 1 });
+    }
+    function.chunk.constants.extend_from_slice(constants);
+    loxc::write(&function, &VM::new())
+}
+
+fn read_error(bytes: &[u8]) -> LoadError {
+    loxc::read(bytes, &mut VM::new()).expect_err("the script should be rejected")
+}
+
+fn invalid(message: &str) -> LoadError {
+    LoadError::Invalid(message.to_string())
+}
+
+#[test]
+fn round_trips_long_constants() {
+    // Past 256 constants the compiler switches to the long forms of the constant instructions.
+    let mut source = String::from("var sum = 0;\n");
+    for i in 0..300 {
+        source += &format!("sum = sum + {};\n", i * 3 + 1);
+    }
+    source += "var last = \"after the long constants\";\nprint sum;\nprint last;\n";
+    assert_round_trips(&source);
+}
+
+#[test]
+fn round_trips_closures_with_captured_variables() {
+    assert_round_trips(
+        "
+        fun counter(start) {
+          var count = start;
+          fun increment() {
+            count = count + 1;
+            return count;
+          }
+          return increment;
+        }
+
+        var a = counter(10);
+        var b = counter(20);
+        a();
+        print a();
+        print b();
+
+        for (var i = 0; i < 3; i = i + 1) {
+          var j = i;
+          fun show() { print j; }
+          show();
+        }
+        ",
+    );
+}
+
+#[test]
+fn round_trips_classes() {
+    assert_round_trips(
+        "
+        class Shape {
+          init(name) { this.name = name; }
+          describe() { return this.name + \" of side \" + this.sideName(); }
+        }
+
+        class Square < Shape {
+          init(side) {
+            super.init(\"square\");
+            this.side = side;
+          }
+          area() { return this.side * this.side; }
+          sideName() { if (this.side == 3) return \"three\"; return \"more\"; }
+          describe() { return \"a \" + super.describe(); }
+        }
+
+        var square = Square(3);
+        print square.describe();
+        square.side = 4;
+        print square.area();
+        ",
+    );
+}
+
+#[test]
+fn rejects_bad_magic() {
+    let mut bytes = write_source("print 1;");
+    bytes[..MAGIC.len()].copy_from_slice(b"LOXX");
+    assert_eq!(read_error(&bytes), LoadError::BadMagic);
+}
+
+#[test]
+fn rejects_other_versions() {
+    let mut bytes = write_source("print 1;");
+    bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert_eq!(read_error(&bytes), LoadError::UnsupportedVersion(VERSION + 1));
+}
+
+#[test]
+fn rejects_constant_index_out_of_bounds() {
+    let bytes = write_code(&[OpCode::Constant as u8, 1, OpCode::Return as u8], &[Value::NIL]);
+    assert_eq!(read_error(&bytes), invalid("constant index out of bounds"));
+}
+
+#[test]
+fn rejects_jump_outside_the_code() {
+    let code = [OpCode::Jump as u8, 0, 10, OpCode::Nil as u8, OpCode::Return as u8];
+    assert_eq!(read_error(&write_code(&code, &[])), invalid("jump target is not an instruction"));
+}
+
+#[test]
+fn rejects_stack_underflow() {
+    // The script's frame starts with only the script itself on the stack.
+    let code = [OpCode::Pop as u8, OpCode::Pop as u8, OpCode::Nil as u8, OpCode::Return as u8];
+    assert_eq!(read_error(&write_code(&code, &[])), invalid("instruction reaches below its frame"));
+}
+
+#[test]
+fn rejects_bad_captures() {
+    let mut heap = Heap::new();
+    let mut inner = Function::new();
+    inner.upvalue_count = 1;
+    inner.chunk.write(OpCode::Nil as u8, Position::default());
+    inner.chunk.write(OpCode::Return as u8, Position::default());
+    let inner = Value::obj(Obj::Function(heap.alloc(inner)));
+
+    let capture = |is_local: u8, index: u8| {
+        let code = [
+            OpCode::Closure as u8, 0, is_local, index,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        read_error(&write_code(&code, &[inner]))
+    };
+    // The script has no upvalues of its own to pass on.
+    assert_eq!(capture(0, 0), invalid("captured upvalue out of bounds"));
+    assert_eq!(capture(1, 5), invalid("captured local is beyond the stack"));
+    assert_eq!(capture(2, 0), invalid("captured upvalue out of bounds"));
+}
+
+/// Writes a script with `depth` functions nested in each other, counting the script.
+fn write_nested(depth: usize) -> Vec<u8> {
+    let mut heap = Heap::new();
+    let returns_nil = || {
+        let mut function = Function::new();
+        function.chunk.write(OpCode::Nil as u8, Position::default());
+        function.chunk.write(OpCode::Return as u8, Position::default());
+        function
+    };
+    let mut function = returns_nil();
+    for _ in 1..depth {
+        let mut outer = returns_nil();
+        outer.chunk.constants.push(Value::obj(Obj::Function(heap.alloc(function))));
+        function = outer;
+    }
+    loxc::write(&function, &VM::new())
+}
+
+#[test]
+fn rejects_functions_nested_too_deeply() {
+    assert!(loxc::read(&write_nested(MAX_DEPTH), &mut VM::new()).is_ok());
+    assert_eq!(read_error(&write_nested(MAX_DEPTH + 1)), invalid("functions nested too deeply"));
+}
+
+#[test]
+fn rejected_files_add_no_globals() {
+    let mut bytes = write_source("var a = 1; var b = 2;");
+    bytes.push(0);
+    let mut vm = VM::new();
+    assert_eq!(loxc::read(&bytes, &mut vm).unwrap_err(), invalid("trailing bytes after the script"));
+    // The header of a written file lists every global the VM knows.
+    assert_eq!(loxc::write(&Function::new(), &vm), loxc::write(&Function::new(), &VM::new()));
+}
+
+#[test]
+fn loads_into_a_vm_with_many_globals() {
+    // Compiled on a fresh VM, so every global instruction uses its short form.
+    let bytes = write_source(
+        "
+        var a = 1;
+        fun double(b) { return a + b; }
+        while (a < 10) a = double(a);
+        if (a > 5) print a; else print \"small\";
+        ",
+    );
+
+    let output = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    let mut defines = String::new();
+    for i in 0..300 {
+        defines += &format!("var g{} = {};\n", i, i);
+    }
+    assert!(matches!(vm.interpret(&defines), InterpretResult::Ok));
+
+    let function = loxc::read(&bytes, &mut vm).expect("the script should load");
+    assert!(matches!(vm.interpret_function(function), InterpretResult::Ok));
+    assert!(matches!(vm.interpret("print g299;"), InterpretResult::Ok));
+    assert_eq!(output.contents(), "16\n299\n");
+}
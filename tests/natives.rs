@@ -0,0 +1,121 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crafting_interpreters::bytecode::native::{FromLox, IntoLox, NativeModule};
+use crafting_interpreters::bytecode::value::Value;
+use crafting_interpreters::bytecode::vm::{InterpretResult, VM};
+
+mod common;
+
+use common::vm_with_output;
+
+fn runtime_error(vm: &mut VM, source: &str) -> String {
+    let InterpretResult::RuntimeError(error) = vm.interpret(source) else {
+        panic!("expected a runtime error");
+    };
+    error.message
+}
+
+#[test]
+fn natives_keep_host_state() {
+    let (mut vm, output) = vm_with_output();
+    let logged = Rc::new(RefCell::new(Vec::new()));
+    let log = Rc::clone(&logged);
+    vm.define_native("log", 1, move |_, args| {
+        log.borrow_mut().push(String::from_lox(args[0])?);
+        Ok(Value::NIL)
+    });
+    let mut count = 0.0;
+    vm.define_native("next", 0, move |vm, _| {
+        count += 1.0;
+        Ok(count.into_lox(vm))
+    });
+
+    let result = vm.interpret("log(\"first\"); log(\"second\"); next(); print next();");
+    assert!(matches!(result, InterpretResult::Ok));
+    assert_eq!(*logged.borrow(), ["first", "second"]);
+    assert_eq!(output.contents(), "2\n");
+}
+
+#[test]
+fn modules_are_globals_with_a_method_per_native() {
+    let (mut vm, output) = vm_with_output();
+    let store = Rc::new(RefCell::new(HashMap::new()));
+    let mut config = NativeModule::new("config");
+    let set_store = Rc::clone(&store);
+    config
+        .define("set", 2, move |_, args| {
+            set_store.borrow_mut().insert(String::from_lox(args[0])?, String::from_lox(args[1])?);
+            Ok(Value::NIL)
+        })
+        .define("get", 1, move |vm, args| {
+            let value = store.borrow().get(&String::from_lox(args[0])?).cloned();
+            Ok(value.into_lox(vm))
+        });
+    vm.register_module(config);
+
+    let source = "
+        config.set(\"name\", \"lox\");
+        print config.get(\"name\");
+        print config.get(\"missing\");
+        var get = config.get;
+        print get(\"name\");
+    ";
+    assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+    assert_eq!(output.contents(), "lox\nnil\nlox\n");
+}
+
+#[test]
+fn conversions_between_lox_and_rust() {
+    let (mut vm, output) = vm_with_output();
+    vm.define_native("describe", 3, |vm, args| {
+        let number = f64::from_lox(args[0])?;
+        let flag = bool::from_lox(args[1])?;
+        let name = Option::<String>::from_lox(args[2])?;
+        let description = format!("{} {} {}", number, flag, name.as_deref().unwrap_or("nobody"));
+        Ok(description.as_str().into_lox(vm))
+    });
+
+    let source = "print describe(1.5, true, \"lox\"); print describe(2, false, nil);";
+    assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+    assert_eq!(output.contents(), "1.5 true lox\n2 false nobody\n");
+
+    assert_eq!(runtime_error(&mut vm, "describe(true, true, nil);"), "Expected a number but got true.");
+    assert_eq!(runtime_error(&mut vm, "describe(1, 1, nil);"), "Expected a boolean but got 1.");
+    assert_eq!(runtime_error(&mut vm, "describe(1, true, 2);"), "Expected a string but got 2.");
+}
+
+#[test]
+fn native_errors_and_arity_are_runtime_errors() {
+    let (mut vm, _) = vm_with_output();
+    vm.define_native("fail", 0, |_, _| Err("The host said no.".to_string()));
+    vm.define_native("one", 1, |_, args| Ok(args[0]));
+
+    assert_eq!(runtime_error(&mut vm, "fail();"), "The host said no.");
+    assert_eq!(runtime_error(&mut vm, "one(1, 2);"), "Expected 1 arguments but got 2.");
+    // The VM is still usable after either.
+    assert!(matches!(vm.interpret("one(1);"), InterpretResult::Ok));
+}
+
+#[test]
+fn converted_strings_survive_until_they_reach_the_stack() {
+    let (mut vm, output) = vm_with_output();
+    // Each string is big enough that a few hundred of them make the VM collect.
+    vm.define_native("first", 0, |vm, _| {
+        let first = "first".into_lox(vm);
+        for i in 0..500 {
+            format!("{:10000}", i).into_lox(vm);
+        }
+        Ok(first)
+    });
+    assert!(matches!(vm.interpret("fun show(s) { print s; } print first();"), InterpretResult::Ok));
+
+    let host = "host".into_lox(&mut vm);
+    for i in 0..500 {
+        format!("{:10000}", i).into_lox(&mut vm);
+    }
+    let result = vm.call_global("show", &[host]).unwrap();
+    vm.release(result);
+    assert_eq!(output.contents(), "first\nhost\n");
+}
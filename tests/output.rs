@@ -0,0 +1,64 @@
+use crafting_interpreters::bytecode::vm::{InterpretResult, VMOptions, VM};
+use crafting_interpreters::output::SharedBuffer;
+use crafting_interpreters::treewalk::{self, Interpreter};
+
+#[test]
+fn vm_writes_output_and_diagnostics_to_their_sinks() {
+    let output = SharedBuffer::new();
+    let diagnostics = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    vm.set_diagnostics(diagnostics.clone());
+
+    let result = vm.interpret("print \"before\";\nprint nil + 1;\nprint \"after\";");
+    vm.report(&result).unwrap();
+    assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    assert_eq!(output.contents(), "before\n");
+    assert_eq!(diagnostics.contents(), "Operands must be two numbers or two strings.\n[2:11] in script\n");
+
+    diagnostics.clear();
+    let result = vm.interpret("var 1 = 2;");
+    vm.report(&result).unwrap();
+    assert_eq!(output.contents(), "before\n");
+    assert_eq!(diagnostics.contents(), "[1:5] Error at '1': Expect variable name.\n");
+}
+
+#[test]
+fn vm_traces_to_its_own_sink() {
+    let output = SharedBuffer::new();
+    let trace = SharedBuffer::new();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    vm.set_trace_output(trace.clone());
+    vm.set_options(VMOptions {
+        trace_execution: true,
+        print_code: true,
+        ..VMOptions::default()
+    });
+
+    assert!(matches!(vm.interpret("var a = 1;\nprint a + 2;"), InterpretResult::Ok));
+    assert_eq!(output.contents(), "3\n");
+    let trace = trace.contents();
+    assert!(trace.contains("== <script> =="), "{}", trace);
+    assert!(trace.contains("OpAdd"), "{}", trace);
+    assert!(trace.contains("[ 1 ][ 2 ]"), "{}", trace);
+}
+
+#[test]
+fn tree_walker_writes_output_and_diagnostics_to_their_sinks() {
+    let output = SharedBuffer::new();
+    let diagnostics = SharedBuffer::new();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(output.clone());
+    interpreter.set_diagnostics(diagnostics.clone());
+
+    treewalk::run(&mut interpreter, "print 1;\nprint nil + 1;\nprint 2;");
+    assert_eq!(output.contents(), "1\n");
+    assert!(diagnostics.contents().contains("Operands must be two numbers or two strings."));
+
+    diagnostics.clear();
+    treewalk::run(&mut interpreter, "var 1 = 2;");
+    assert_eq!(output.contents(), "1\n");
+    let diagnostics = diagnostics.contents();
+    assert!(diagnostics.contains("Error at \"1\": Expect variable name."), "{}", diagnostics);
+}
@@ -0,0 +1,114 @@
+//! Runs the scripts in the `test/` directories that check the command line's own behavior: its
+//! flags, and what it writes to stderr.
+//!
+//! Besides the `// expect:`, `// expect runtime error:` and `// Error ...` comments of the rest of
+//! the suite, these scripts can use:
+//!
+//! - `// args: ...`, flags to pass before the script's path.
+//! - `// expect stderr: ...`, once for every line expected on stderr. Stderr then has to match
+//!   these lines exactly.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Directories under `test/` whose scripts this runs.
+const DIRECTORIES: &[&str] = &["cache", "diagnostics", "field", "folding", "fuel", "optimizer"];
+
+#[derive(Default)]
+struct Expectations {
+    args: Vec<String>,
+    output: Vec<String>,
+    stderr: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<String>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expectations = Expectations::default();
+        for line in source.lines() {
+            let Some(start) = line.find("// ") else {
+                continue;
+            };
+            let comment = &line[start + 3..];
+            if let Some(rest) = comment.strip_prefix("expect stderr:") {
+                expectations.stderr.push(rest.strip_prefix(' ').unwrap_or(rest).to_string());
+            } else if let Some(rest) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some(rest.to_string());
+            } else if let Some(rest) = comment.strip_prefix("expect:") {
+                expectations.output.push(rest.strip_prefix(' ').unwrap_or(rest).to_string());
+            } else if let Some(rest) = comment.strip_prefix("args: ") {
+                expectations.args.extend(rest.split_whitespace().map(String::from));
+            } else if comment.starts_with("Error") {
+                expectations.compile_errors.push(comment.to_string());
+            }
+        }
+        expectations
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+fn check(path: &Path) -> Result<(), String> {
+    let expectations = Expectations::parse(&fs::read_to_string(path).unwrap());
+    let result = Command::new(env!("CARGO_BIN_EXE_crafting-interpreters"))
+        .args(&expectations.args)
+        .arg(path)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    let output = String::from_utf8_lossy(&result.stdout);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    let stderr: Vec<_> = stderr.lines().collect();
+
+    if result.status.code() != Some(expectations.exit_code()) {
+        return Err(format!("exited with {}, stderr: {:?}", result.status, stderr));
+    }
+    let output: Vec<_> = output.lines().collect();
+    if output != expectations.output {
+        return Err(format!("printed {:?}, expected {:?}", output, expectations.output));
+    }
+    if let Some(message) = &expectations.runtime_error {
+        if stderr.first() != Some(&message.as_str()) {
+            return Err(format!("failed with {:?}, expected {:?}", stderr, message));
+        }
+    }
+    for error in &expectations.compile_errors {
+        if !stderr.contains(&error.as_str()) {
+            return Err(format!("reported {:?}, expected {:?}", stderr, error));
+        }
+    }
+    if !expectations.stderr.is_empty() && stderr != expectations.stderr {
+        return Err(format!("wrote {:?} to stderr, expected {:?}", stderr, expectations.stderr));
+    }
+    Ok(())
+}
+
+#[test]
+fn scripts() {
+    let mut failures = Vec::new();
+    for directory in DIRECTORIES {
+        // Relative to the crate, which is where the scripts run, so errors show short paths.
+        let directory = Path::new("test").join(directory);
+        let mut paths: Vec<_> = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join(&directory))
+            .unwrap()
+            .map(|entry| directory.join(entry.unwrap().file_name()))
+            .collect();
+        paths.sort();
+        for path in paths {
+            if let Err(message) = check(&path) {
+                failures.push(format!("{}: {}", path.display(), message));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}